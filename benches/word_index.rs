@@ -0,0 +1,63 @@
+// What the buffer source does on every keystroke, on buffers of different sizes:
+// re-tokenizing the line that changed, and ranking the buffer's words against
+// what's been typed (first-character buckets from the `Trie`, fuzzy scoring, and
+// the distance from the cursor to each match).
+//
+// Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rofl_nvim::word_store::BufferWordStore;
+
+/// Something shaped like a big generated file: lines of identifiers, with lots of
+/// words sharing a handful of prefixes.
+fn generated_line(line: usize) -> Vec<String> {
+    let prefixes = ["get_", "set_", "has_", "field_", "Message", "proto_"];
+
+    (0..8)
+        .map(|i| {
+            let n = line * 8 + i;
+            format!("{}{}_{}", prefixes[n % prefixes.len()], n / 7, n % 13)
+        })
+        .collect()
+}
+
+fn generated_buffer(lines: usize) -> BufferWordStore {
+    let mut store = BufferWordStore::default();
+    store.splice(0, 0, (0..lines).map(generated_line).collect());
+    store
+}
+
+fn bench_ranked_matches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ranked_matches");
+
+    for lines in [1_000, 10_000, 50_000].iter() {
+        let store = generated_buffer(*lines);
+        let cursor_line = Some(*lines as u64 / 2);
+
+        for pattern in ["g", "gs1", "MsgF"].iter() {
+            group.bench_with_input(BenchmarkId::new(*pattern, lines), pattern, |b, pattern| {
+                b.iter(|| store.get_ranked_matches(black_box(pattern), cursor_line))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_splice(c: &mut Criterion) {
+    let mut store = generated_buffer(10_000);
+    let line = generated_line(5_000);
+    let mut edited = line.clone();
+    edited.push(String::from("get_typed_word"));
+
+    // Typing in the middle of the buffer, one line changes back and forth
+    c.bench_function("splice/one_line_of_10000", |b| {
+        b.iter(|| {
+            store.splice(5_000, 5_001, vec![edited.clone()]);
+            store.splice(5_000, 5_001, vec![line.clone()]);
+        })
+    });
+}
+
+criterion_group!(benches, bench_ranked_matches, bench_splice);
+criterion_main!(benches);
@@ -12,11 +12,89 @@ if 0 == vim.fn.executable(binary_path) then
     .. "/target/release/rofl_nvim"
 end
 
+--- Has to match `PROTOCOL_VERSION` in src/rpc.rs
+rofl.protocol_version = 3
+
+--- Ask the server what it is, and stop it if it doesn't speak our protocol,
+--- which usually means the binary is older than this file.
+local handshake = function()
+  local ok, info = pcall(vim.rpcrequest, rofl.job_id, "initialize", rofl.protocol_version)
+
+  local problem
+  if not ok then
+    problem = "it failed to initialize: " .. tostring(info)
+  elseif type(info) ~= "table" or info.protocol ~= rofl.protocol_version then
+    problem = string.format(
+      "it speaks protocol %s, but we need %s",
+      type(info) == "table" and tostring(info.protocol) or "?",
+      rofl.protocol_version
+    )
+  end
+
+  if problem then
+    vim.notify(
+      string.format("[rofl] Not using %s, %s. Try rebuilding it with `cargo build`.", binary_path, problem),
+      vim.log.levels.ERROR
+    )
+    vim.fn.jobstop(rofl.job_id)
+    rofl.job_id = nil
+    return false
+  end
+
+  rofl.server_info = info
+  return true
+end
+
+--- Send `rofl.config` to the server. Returns false, after telling you why, if it was refused.
+local send_config = function()
+  local ok, err = pcall(vim.rpcrequest, rofl.job_id, "setup", rofl.config)
+  if not ok then
+    vim.notify("[rofl] Bad config: " .. tostring(err), vim.log.levels.ERROR)
+  end
+
+  return ok
+end
+
+--- Configure the server, e.g.
+---
+---   require('rofl').setup {
+---     max_items = 50,
+---     timeout_ms = 200,
+---     sources = { buffer = { scope = "visible" }, file = { timeout_ms = 500 } },
+---     filetypes = {
+---       rust = { sources = { lsp = { priority = 30 } } },
+---       gitcommit = { timeout_ms = 50, sources = { file = false } },
+---     },
+---   }
+---
+--- `max_items` caps the whole list, and `timeout_ms` is how long each source gets
+--- before its results are dropped. Every source also takes `priority`, `max_items`
+--- and `timeout_ms` of its own, which win over the global ones.
+---
+--- `sources` are used when a request doesn't say which it wants, and `filetypes`
+--- go on top of them for buffers of that 'filetype', which the server keeps track
+--- of. `rofl/config.toml` in your config dir takes the same settings, for when
+--- there's no Lua to call this.
+rofl.setup = function(config)
+  rofl.config = config or {}
+
+  if rofl.job_id then
+    return send_config()
+  end
+
+  return true
+end
+
 rofl.start = function(bufnr)
   bufnr = bufnr or 0
 
   if rofl.job_id then
-    return
+    return true
+  end
+
+  -- Don't keep starting a binary we already turned down
+  if rofl.refused then
+    return false
   end
 
   rofl.job_id = vim.fn.jobstart(
@@ -26,14 +104,46 @@ rofl.start = function(bufnr)
     }
   )
   print("Making new job...", rofl.job_id)
+
+  if not handshake() then
+    rofl.refused = true
+    return false
+  end
+
+  if rofl.config then
+    send_config()
+  end
+
   print("Request Result: ", rofl.request(
     "buf_initialize",
     vim.api.nvim_get_current_buf(),
-    vim.bo.iskeyword
+    vim.bo.iskeyword,
+    vim.o.isfname,
+    vim.bo.filetype,
+    vim.o.isident,
+    vim.o.isprint
   ))
+  return true
 end
 
 local attached = {}
+
+--- Every `nvim_buf_attach` callback we forward gets the next number for its buffer.
+--- One change can have several callbacks with the same changedtick, e.g. `:s///g`,
+--- so this is what the server puts them in order by.
+local seqs = {}
+
+local next_seq = function(bufnr)
+  seqs[bufnr] = (seqs[bufnr] or 0) + 1
+  return seqs[bufnr]
+end
+
+--- The number of the last callback forwarded for `bufnr`, which the server gets
+--- along with the whole buffer when it syncs.
+rofl._get_seq = function(bufnr)
+  return seqs[bufnr] or 0
+end
+
 rofl.attach = function(bufnr)
   bufnr = bufnr or vim.api.nvim_get_current_buf()
   if attached[bufnr] then
@@ -41,35 +151,190 @@ rofl.attach = function(bufnr)
   end
 
   attached[bufnr] = true
+  seqs[bufnr] = 0
+
+  -- The server splits lines into words with the buffer's 'iskeyword', and paths with 'isfname'
+  -- ('isident' and 'isprint' are there for sources that want them).
+  -- 'filetype' picks which sources it completes with, unless a request says otherwise.
+  rofl.notify(
+    "buf_initialize",
+    bufnr,
+    vim.bo[bufnr].iskeyword,
+    vim.o.isfname,
+    vim.bo[bufnr].filetype,
+    vim.o.isident,
+    vim.o.isprint
+  )
+
+  -- The server keeps its own copy of the buffer. It gets the whole thing when we
+  -- initialize (or whenever it falls out of sync), and from then on only the bytes
+  -- that changed, numbered so it can tell if it missed anything.
+  api.nvim_buf_attach(bufnr, false, {
+    on_bytes = function(_, byte_bufnr, changedtick, start_row, start_col, _, old_end_row, old_end_col, _, new_end_row, new_end_col, _)
+      -- End positions are relative to the start, and so is the column when it's on the same row
+      local end_row = start_row + new_end_row
+      local end_col = new_end_col
+      if new_end_row == 0 then
+        end_col = start_col + new_end_col
+      end
+
+      rofl.notify(
+        "buf_attach_bytes",
 
-  -- vim.cmd [[autocmd! InsertCharPre <buffer> lua require'rofl'.notify("v_char", vim.api.nvim_get_vvar("char"))]]
-  -- vim.cmd [[autocmd! InsertLeave <buffer> lua require'rofl'.notify("insert_leave")]]
+        byte_bufnr,
+        changedtick,
+        next_seq(byte_bufnr),
 
-  api.nvim_buf_attach(bufnr, true, {
-    on_lines = function(_, line_bufnr, _, line_start, line_end, new_end)
-      local lines = vim.api.nvim_buf_get_lines(bufnr, line_start, new_end, false)
-      -- local mode =  api.nvim_get_mode()["mode"]
-      -- rofl.notify("complete")
-      -- vim.schedule(function() 
-        rofl.notify(
-          "buf_attach_lines",
+        -- Range that was replaced
+        start_row,
+        start_col,
+        old_end_row,
+        old_end_col,
+
+        -- Where the new text ends
+        new_end_row,
+        new_end_col,
+
+        -- New text, split into lines
+        rofl._get_text(byte_bufnr, start_row, start_col, end_row, end_col)
+      )
+    end,
 
-          line_bufnr,
+    on_changedtick = function(_, tick_bufnr, changedtick)
+      rofl.notify("buf_changedtick", tick_bufnr, changedtick, next_seq(tick_bufnr))
+    end,
 
-          -- Range of start, finish
-          line_start,
-          line_end,
+    -- `:edit` and friends replace the whole buffer without telling us what changed
+    on_reload = function(_, reload_bufnr)
+      rofl.notify("buf_reload", reload_bufnr)
+    end,
 
-          -- New text in the lines
-          lines
-        )
-      -- end)
+    -- Unloading the buffer detaches us, so the server can let go of it
+    on_detach = function(_, detach_bufnr)
+      attached[detach_bufnr] = nil
+      seqs[detach_bufnr] = nil
+      rofl.notify("buf_detach", detach_bufnr)
     end,
   })
+
+  vim.cmd(string.format([[
+    augroup rofl_buffer_%d
+      autocmd!
+      autocmd BufWipeout <buffer=%d> lua require('rofl')._on_wipe(%d)
+      autocmd OptionSet iskeyword lua require('rofl')._on_iskeyword(%d)
+      autocmd FileType <buffer=%d> lua require('rofl')._on_filetype(%d)
+      autocmd TextChangedI,TextChangedP <buffer=%d> lua require('rofl')._on_text_changed(%d)
+      autocmd InsertLeave <buffer=%d> lua require('rofl').cancel()
+    augroup END
+  ]], bufnr, bufnr, bufnr, bufnr, bufnr, bufnr, bufnr, bufnr, bufnr))
+end
+
+rofl._on_wipe = function(bufnr)
+  attached[bufnr] = nil
+  seqs[bufnr] = nil
+  vim.cmd(string.format("autocmd! rofl_buffer_%d", bufnr))
+  rofl.notify("buf_wipe", bufnr)
+end
+
+--- `OptionSet` isn't buffer-local, so check that it was this buffer's 'iskeyword' that changed.
+rofl._on_iskeyword = function(bufnr)
+  if api.nvim_get_current_buf() ~= bufnr then
+    return
+  end
+
+  rofl.notify("buf_iskeyword", bufnr, vim.bo[bufnr].iskeyword)
+end
+
+--- A buffer usually gets its 'filetype' after we've attached to it, e.g. `:edit` of a new file.
+rofl._on_filetype = function(bufnr)
+  rofl.notify("buf_filetype", bufnr, vim.bo[bufnr].filetype)
+end
+
+--- Set to false to only complete when asked to, with 'completefunc'
+rofl.auto_complete = true
+
+--- Something was typed, so ask for completions. They come back through
+--- `_show_completions` whenever they're ready, so typing is never held up.
+rofl._on_text_changed = function(bufnr)
+  if not rofl.auto_complete or api.nvim_get_current_buf() ~= bufnr then
+    return
+  end
+
+  -- <C-n>/<C-p> put the selected item in the buffer, which counts as a change too,
+  -- and asking again would replace the menu and lose the selection
+  if vim.fn.complete_info({ 'selected' }).selected ~= -1 then
+    return
+  end
+
+  rofl.notify(
+    "complete",
+    rofl._get_context({
+      request_id = rofl._next_request_id(),
+      changedtick = api.nvim_buf_get_changedtick(bufnr),
+      line = api.nvim_get_current_line(),
+    }),
+    rofl._get_sources(),
+    vim.b.rofl_complete_source
+  )
+end
+
+--- Completion requests are numbered, and each one makes the server drop the ones before it
+local last_request_id = 0
+
+rofl._next_request_id = function()
+  last_request_id = last_request_id + 1
+  return last_request_id
+end
+
+--- Stop working on every completion request sent so far, e.g. since we left insert mode.
+rofl.cancel = function()
+  if rofl.job_id and last_request_id > 0 then
+    vim.rpcnotify(rofl.job_id, "cancel", last_request_id)
+  end
+end
+
+--- Called by the server with completions for what was typed at `changedtick`.
+--- If anything has happened since then they're out of date, so they're dropped.
+rofl._show_completions = function(bufnr, changedtick, cursor, startcol, items)
+  if api.nvim_get_current_buf() ~= bufnr
+    or api.nvim_buf_get_changedtick(bufnr) ~= changedtick
+    or api.nvim_get_mode().mode:sub(1, 1) ~= "i" then
+    return false
+  end
+
+  local current = api.nvim_win_get_cursor(0)
+  if current[1] ~= cursor[1] or current[2] ~= cursor[2] then
+    return false
+  end
+
+  -- `complete()` columns are 1-indexed
+  vim.fn.complete(startcol + 1, items)
+  return true
+end
+
+--- Like `nvim_buf_get_text`, but the end can be the row after the last line,
+--- which is where `on_bytes` says text ends when it ends with a newline.
+rofl._get_text = function(bufnr, start_row, start_col, end_row, end_col)
+  local line_count = api.nvim_buf_line_count(bufnr)
+  if end_row < line_count then
+    return api.nvim_buf_get_text(bufnr, start_row, start_col, end_row, end_col, {})
+  end
+
+  if start_row >= line_count then
+    return { "" }
+  end
+
+  local last_line = api.nvim_buf_get_lines(bufnr, line_count - 1, line_count, false)[1]
+  local text = api.nvim_buf_get_text(bufnr, start_row, start_col, line_count - 1, #last_line, {})
+  table.insert(text, "")
+  return text
 end
 
 rofl.request = function(method, ...)
-  rofl.start()
+  if not rofl.start() then
+    return nil
+  end
+
   local result = vim.rpcrequest(rofl.job_id, method, ...)
   print("Result:", method, vim.inspect(result))
   return result
@@ -88,37 +353,62 @@ rofl.complete_func = function(find_start, base)
       vim.api.nvim_get_current_line(),
 
       -- Cursor column
-      vim.api.nvim_win_get_cursor(0)[2]
-    )
-  else
-    return rofl.request(
-      'complete',
+      vim.api.nvim_win_get_cursor(0)[2],
 
-      -- And more...
-      base
+      -- Source being completed, which decides what counts as the word, e.g. "file" for paths
+      vim.b.rofl_complete_source
     )
+  else
+    -- The buffer may not have `base` in it any more, so it's all the line we give the sources
+    return rofl._get_completions({
+      context = {
+        word = base,
+        line = base,
+        cursor = { api.nvim_win_get_cursor(0)[1], #base },
+      },
+    })
   end
 end
 
 
 rofl.notify = function(method, ...)
-  rofl.start()
+  if not rofl.start() then
+    return
+  end
+
   vim.rpcnotify(rofl.job_id, method, ...)
 end
 
+--- Called by the server when a source said its results were incomplete.
+rofl.refresh = function(source, bufnr)
+  if source == "lsp" then
+    require('rofl.lsp').request(bufnr)
+  end
+end
+
 rofl._get_context = function(ctx)
   return vim.tbl_deep_extend("force", {
     word = vim.fn.expand("<cword>"),
     cwd = vim.loop.cwd(),
     bufnr = vim.api.nvim_get_current_buf(),
+    cursor = vim.api.nvim_win_get_cursor(0),
+    visible = vim.tbl_map(api.nvim_win_get_buf, api.nvim_tabpage_list_wins(0)),
   }, ctx)
 end
 
+--- `vim.b.rofl_sources` picks the sources for one buffer. Otherwise it's nil, so
+--- the server uses the ones from `setup`.
+rofl._get_sources = function()
+  return vim.b.rofl_sources
+end
+
 rofl._get_completions = function(req)
   return rofl.request(
     'complete_sync'
 
-    , rofl._get_context(req.context)
+    , rofl._get_context(vim.tbl_extend("keep", req.context or {}, {
+      request_id = rofl._next_request_id(),
+    }))
     , req.sources or rofl._get_sources()
   )
 end
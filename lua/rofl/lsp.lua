@@ -0,0 +1,36 @@
+local api = vim.api
+
+local rofl = require('rofl')
+
+local lsp = {}
+
+--- Ask the language servers attached to `bufnr` for completions at the cursor,
+--- and forward whatever they answer to the `lsp` source.
+lsp.request = function(bufnr)
+  bufnr = bufnr or api.nvim_get_current_buf()
+
+  -- Positions only make sense for the buffer we're looking at
+  if bufnr ~= api.nvim_get_current_buf() then
+    return
+  end
+
+  local params = vim.lsp.util.make_position_params()
+  local before_cursor = api.nvim_get_current_line():sub(1, api.nvim_win_get_cursor(0)[2])
+  local word = vim.fn.matchstr(before_cursor, [[\k*$]])
+
+  vim.lsp.buf_request(bufnr, 'textDocument/completion', params, function(err, method_or_result, result_or_ctx)
+    -- Handlers used to be called with (err, method, result), now it's (err, result, ctx)
+    local result = method_or_result
+    if type(method_or_result) == 'string' then
+      result = result_or_ctx
+    end
+
+    if err or not result then
+      return
+    end
+
+    rofl.notify("source_notify", "lsp", "completion_response", bufnr, params.position, word, result)
+  end)
+end
+
+return lsp
@@ -19,7 +19,7 @@ local get_buffer_completions = function(word, disable_buffer)
     }
   }
 
-  table.sort(res)
+  res = vim.tbl_map(function(item) return item.word end, res)
 
   return res
 end
@@ -45,19 +45,34 @@ describe('rofl.nvim files', function()
   end)
 
   it('returns things only from the current buffer', function()
-    --[[
-      hello world
-
-      ----------------
-
-      goodnight moon
-
-    --]]
-
     --[[    ^n -> rofl.compete { buffers = { current_buf } },
     --      ^x^n -> rofl.complete { buffers = { all_visible } }
     --      <M-n> -> rofl.complete { buffers = { all } }
     --]]
+    local current = vim.api.nvim_get_current_buf()
+    vim.api.nvim_buf_set_lines(current, 0, -1, false, {"hello world"})
+
+    local other = vim.api.nvim_create_buf(true, false)
+    rofl.attach(other)
+    vim.api.nvim_buf_set_lines(other, 0, -1, false, {"goodnight moon", "hello again"})
+
+    local complete = function(word, scope)
+      local res = rofl._get_completions {
+        context = get_context(word),
+        sources = { buffer = { scope = scope } },
+      }
+
+      res = vim.tbl_map(function(item) return item.word end, res)
+      return res
+    end
+
+    eq({}, complete("goo", "current"))
+    eq({}, complete("goo", "visible"))
+    eq({"goodnight"}, complete("goo", "all"))
+    eq({"goodnight"}, complete("goo", { other }))
+
+    -- Only once, even though both buffers have it
+    eq({"hello"}, complete("hel", "all"))
   end)
 
   it('returns valid candidates in a file', function()
@@ -3,6 +3,13 @@ local rofl = require('rofl')
 local eq = assert.are.same
 
 describe('rofl.nvim connection', function()
+  it('agrees on the protocol', function()
+    assert(rofl.start())
+    eq(rofl.protocol_version, rofl.server_info.protocol)
+    assert(vim.tbl_contains(rofl.server_info.sources, "buffer"))
+    assert(vim.tbl_contains(rofl.server_info.requests, "complete_sync"))
+  end)
+
   -- it('can connect and send a test request', function()
   --   eq(true, rofl.request("_test"))
   -- end)
@@ -10,30 +10,28 @@ local get_file_completions = function(word, cwd)
       cwd = cwd or vim.loop.cwd(),
     },
     sources = {
-      files = true
+      file = true
     }
   }
 
-  table.sort(res)
+  res = vim.tbl_map(function(item) return item.word end, res)
 
   return res
 end
 
--- TODO: Should paths show the leading "./" or not?
-
 describe('rofl.nvim files', function()
   it('returns empty list for bad files', function()
     eq({}, get_file_completions('/hello/wor'))
   end)
 
   it('returns one file when it matches', function()
-    -- eq({'./README.md'}, get_file_completions('./README.m'))
-    eq({'README.md'}, get_file_completions('./README.m'))
+    -- The directory you typed stays, since the whole path gets replaced
+    eq({'./README.md'}, get_file_completions('./README.m'))
   end)
 
   it('returns one file when it matches', function()
-    eq({'Cargo.lock', 'Cargo.toml'}, get_file_completions('./Car'))
-    eq({'Cargo.lock', 'Cargo.toml'}, get_file_completions('./Car'))
+    eq({'./Cargo.lock', './Cargo.toml'}, get_file_completions('./Car'))
+    eq({'Cargo.lock', 'Cargo.toml'}, get_file_completions('Car'))
   end)
 
   it('returns files from different cwds', function()
@@ -1,5 +1,208 @@
+use std::collections::BTreeMap;
+
 #[derive(Debug, PartialEq)]
 pub struct LineRange {
     pub start: usize,
+
+    /// Where the cursor really is: clamped to the line, and moved back to the start of
+    /// the character it was in. Always between `start` and `finish`, so
+    /// `&line[start..cursor]` is what's been typed so far.
+    pub cursor: usize,
+
     pub finish: usize,
 }
+
+/// Where the cursor is, with a 0-indexed line and a byte column,
+/// the same way `nvim_buf_get_lines` and friends count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub line: u64,
+    pub col: u64,
+}
+
+/// A prefix tree of words, where each word keeps a count of how many times it was added.
+///
+/// Adding a word that's already there just bumps its count, and removing it only
+/// takes it out of the tree once the count gets back to zero. Looking up words by
+/// prefix only has to walk the prefix and then the words under it, instead of
+/// every word we know about.
+#[derive(Debug, Clone, Default)]
+pub struct Trie {
+    root: TrieNode,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: BTreeMap<char, TrieNode>,
+
+    /// How many times the word ending at this node was added. 0 means it's not a word.
+    count: u64,
+}
+
+impl TrieNode {
+    fn collect(&self, word: &mut String, results: &mut Vec<(String, u64)>) {
+        if self.count > 0 {
+            results.push((word.clone(), self.count));
+        }
+
+        for (c, child) in &self.children {
+            word.push(*c);
+            child.collect(word, results);
+            word.pop();
+        }
+    }
+
+    /// Returns whether the word was there, and prunes any nodes that end up empty.
+    fn remove(&mut self, mut chars: std::str::Chars) -> bool {
+        match chars.next() {
+            None => {
+                if self.count == 0 {
+                    return false;
+                }
+
+                self.count -= 1;
+                true
+            }
+            Some(c) => {
+                let child = match self.children.get_mut(&c) {
+                    Some(child) => child,
+                    None => return false,
+                };
+
+                let removed = child.remove(chars);
+                if child.count == 0 && child.children.is_empty() {
+                    self.children.remove(&c);
+                }
+
+                removed
+            }
+        }
+    }
+}
+
+impl Trie {
+    /// Number of distinct words.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, word: &str) {
+        let node = word.chars().fold(&mut self.root, |node, c| {
+            node.children.entry(c).or_default()
+        });
+
+        if node.count == 0 {
+            self.len += 1;
+        }
+        node.count += 1;
+    }
+
+    /// Takes away one occurrence of `word`. Returns false if it wasn't there at all.
+    pub fn remove(&mut self, word: &str) -> bool {
+        let removed = self.root.remove(word.chars());
+        if removed && self.count(word) == 0 {
+            self.len -= 1;
+        }
+
+        removed
+    }
+
+    pub fn count(&self, word: &str) -> u64 {
+        self.find(word).map_or(0, |node| node.count)
+    }
+
+    pub fn contains(&self, word: &str) -> bool {
+        self.count(word) > 0
+    }
+
+    /// Every word starting with `prefix` (including `prefix` itself), with its count,
+    /// in sorted order.
+    pub fn with_prefix(&self, prefix: &str) -> Vec<(String, u64)> {
+        let mut results = Vec::new();
+        if let Some(node) = self.find(prefix) {
+            node.collect(&mut prefix.to_string(), &mut results);
+        }
+
+        results
+    }
+
+    fn find(&self, prefix: &str) -> Option<&TrieNode> {
+        prefix
+            .chars()
+            .try_fold(&self.root, |node, c| node.children.get(&c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trie_counts_words() {
+        let mut trie = Trie::default();
+        trie.insert("hello");
+        trie.insert("hello");
+        trie.insert("help");
+
+        assert_eq!(2, trie.len());
+        assert_eq!(2, trie.count("hello"));
+        assert_eq!(1, trie.count("help"));
+        assert_eq!(0, trie.count("hel"));
+        assert!(!trie.contains("hel"));
+    }
+
+    #[test]
+    fn test_trie_prefix_lookup() {
+        let mut trie = Trie::default();
+        for word in &["hello", "help", "world", "he"] {
+            trie.insert(word);
+        }
+
+        assert_eq!(
+            vec![
+                (String::from("he"), 1),
+                (String::from("hello"), 1),
+                (String::from("help"), 1)
+            ],
+            trie.with_prefix("he")
+        );
+        assert_eq!(4, trie.with_prefix("").len());
+        assert!(trie.with_prefix("x").is_empty());
+    }
+
+    #[test]
+    fn test_trie_remove() {
+        let mut trie = Trie::default();
+        trie.insert("hello");
+        trie.insert("hello");
+        trie.insert("help");
+
+        assert!(trie.remove("hello"));
+        assert_eq!(1, trie.count("hello"));
+        assert_eq!(2, trie.len());
+
+        assert!(trie.remove("hello"));
+        assert!(!trie.contains("hello"));
+        assert_eq!(1, trie.len());
+        assert_eq!(vec![(String::from("help"), 1)], trie.with_prefix("he"));
+
+        assert!(!trie.remove("hello"));
+        assert!(!trie.remove("hel"));
+        assert_eq!(1, trie.len());
+    }
+
+    #[test]
+    fn test_trie_multibyte() {
+        let mut trie = Trie::default();
+        trie.insert("héllo");
+        trie.insert("日本語");
+
+        assert_eq!(vec![(String::from("héllo"), 1)], trie.with_prefix("hé"));
+        assert_eq!(vec![(String::from("日本語"), 1)], trie.with_prefix("日"));
+    }
+}
@@ -0,0 +1,486 @@
+// Settings for the whole server, as opposed to the ones sent with each request.
+//
+// They come from `require('rofl').setup { ... }`, which sends them with the
+// `setup` request, or from `rofl/config.toml` in the config dir, which is read
+// at startup so the server can be configured without Lua (e.g. when testing it
+// headless). Both have the same shape:
+//
+//     log_level = "info"
+//     max_items = 50
+//     timeout_ms = 200
+//
+//     [sources]
+//     buffer = { scope = "visible" }
+//     file = { timeout_ms = 500 }
+//
+//     [filetypes.rust.sources]
+//     lsp = { priority = 30 }
+//     file = false
+//
+// `sources` are the ones used when a request doesn't say which it wants, and
+// their options are the defaults for what a request sends. Each entry in
+// `filetypes` goes on top of that for buffers of that filetype.
+
+use std::{collections::HashMap, path::Path, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::LevelFilter;
+use nvim_rs::Value;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    rpc::as_table,
+    sources::{MergePolicy, SourceRegistry},
+};
+
+/// Where the config file lives, under the user's config dir.
+pub const CONFIG_FILE: &str = "rofl/config.toml";
+
+/// Used when nothing says which sources to use.
+const DEFAULT_SOURCES: &[&str] = &["buffer", "file"];
+
+/// One entry of a `sources` table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceSetting {
+    /// `file = false`, which turns off a source that'd otherwise be used
+    Off,
+
+    /// `file = true` is the same as `file = {}`
+    On(Value),
+}
+
+impl SourceSetting {
+    /// `None` if it's something that can't turn a source on or off.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Boolean(true) => Some(SourceSetting::On(Value::Map(Vec::new()))),
+            Value::Map(_) => Some(SourceSetting::On(value.clone())),
+            Value::Boolean(false) | Value::Nil => Some(SourceSetting::Off),
+            _ => None,
+        }
+    }
+}
+
+/// A `sources` table, in the order it was written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sources(pub Vec<(String, SourceSetting)>);
+
+impl<'de> Deserialize<'de> for Sources {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let map = as_table(&value).ok_or_else(|| {
+            de::Error::custom(format!("sources should be a table, got {}", value))
+        })?;
+
+        map.iter()
+            .map(|(name, value)| {
+                let name = name
+                    .as_str()
+                    .ok_or_else(|| {
+                        de::Error::custom(format!("source names are strings, got {}", name))
+                    })?
+                    .to_string();
+                let setting = SourceSetting::from_value(value).ok_or_else(|| {
+                    de::Error::custom(format!(
+                        "{} should be a boolean or a table, got {}",
+                        name, value
+                    ))
+                })?;
+
+                Ok((name, setting))
+            })
+            .collect::<Result<_, _>>()
+            .map(Sources)
+    }
+}
+
+impl Sources {
+    /// `over` wins: a table is merged into the options already there, `false` turns it off.
+    fn overlay(&mut self, over: &Sources) {
+        for (name, setting) in &over.0 {
+            match self.0.iter_mut().find(|(existing, _)| existing == name) {
+                Some((_, existing)) => *existing = existing.overlay(setting),
+                None => self.0.push((name.clone(), setting.clone())),
+            }
+        }
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.0.iter().filter_map(|(name, setting)| match setting {
+            SourceSetting::On(opts) => Some((name, opts)),
+            SourceSetting::Off => None,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.enabled()
+            .find(|(existing, _)| *existing == name)
+            .map(|(_, opts)| opts)
+    }
+}
+
+impl SourceSetting {
+    fn overlay(&self, over: &SourceSetting) -> SourceSetting {
+        match (self, over) {
+            (SourceSetting::On(base), SourceSetting::On(over)) => {
+                SourceSetting::On(merge_options(base, over))
+            }
+            (_, over) => over.clone(),
+        }
+    }
+}
+
+/// The keys in `over` replace the ones in `base`.
+fn merge_options(base: &Value, over: &Value) -> Value {
+    let mut merged: Vec<(Value, Value)> = base.as_map().cloned().unwrap_or_default();
+    for (key, value) in over.as_map().map(Vec::as_slice).unwrap_or_default() {
+        match merged.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = value.clone(),
+            None => merged.push((key.clone(), value.clone())),
+        }
+    }
+
+    Value::Map(merged)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FiletypeConfig {
+    pub max_items: Option<usize>,
+    pub timeout_ms: Option<u64>,
+    pub sources: Option<Sources>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: Option<String>,
+
+    /// Only read from the config file, since the log is already open by the time `setup` comes
+    pub log_path: Option<PathBuf>,
+
+    /// How many items a request gives back at most, from every source together
+    pub max_items: Option<usize>,
+
+    /// How long every source gets, unless its own options say otherwise with `timeout_ms`
+    pub timeout_ms: Option<u64>,
+
+    pub sources: Option<Sources>,
+
+    #[serde(deserialize_with = "filetypes")]
+    pub filetypes: HashMap<String, FiletypeConfig>,
+}
+
+/// `filetypes = {}` from Lua is as good as no filetypes.
+fn filetypes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, FiletypeConfig>, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    match as_table(&value) {
+        Some([]) => Ok(HashMap::new()),
+        _ => rmpv::ext::from_value(value).map_err(de::Error::custom),
+    }
+}
+
+impl Config {
+    /// Read the config file, if there is one.
+    pub fn load(path: &Path) -> Result<Option<Config>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("Failed to read {}", path.display())),
+        };
+
+        toml::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn log_level(&self) -> Result<Option<LevelFilter>> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                level
+                    .parse()
+                    .map_err(|_| anyhow!("log_level: unknown level '{}'", level))
+            })
+            .transpose()
+    }
+
+    /// Make sure everything in here means something, so that a mistake is an
+    /// error when setting it up, and not when completing.
+    pub fn validate(&self, registry: &SourceRegistry) -> Result<()> {
+        self.log_level()?;
+
+        let check = |sources: &Option<Sources>, path: &str| -> Result<()> {
+            for (name, opts) in sources.iter().flat_map(Sources::enabled) {
+                registry
+                    .options(name, opts)
+                    .with_context(|| format!("{}.{}", path, name))?;
+            }
+            Ok(())
+        };
+
+        check(&self.sources, "sources")?;
+        for (filetype, config) in &self.filetypes {
+            check(&config.sources, &format!("filetypes.{}.sources", filetype))?;
+        }
+
+        Ok(())
+    }
+
+    /// The sources for `filetype`, with its overrides on top of the global ones.
+    fn sources(&self, filetype: Option<&str>) -> Sources {
+        let mut sources = self.sources.clone().unwrap_or_else(|| {
+            Sources(
+                DEFAULT_SOURCES
+                    .iter()
+                    .map(|name| (name.to_string(), SourceSetting::On(Value::Map(Vec::new()))))
+                    .collect(),
+            )
+        });
+
+        if let Some(overrides) = filetype
+            .and_then(|filetype| self.filetypes.get(filetype))
+            .and_then(|config| config.sources.as_ref())
+        {
+            sources.overlay(overrides);
+        }
+
+        sources
+    }
+
+    /// What a request completes with: the sources it asked for, or the configured
+    /// ones if it didn't ask, with its options on top of the configured ones.
+    pub fn requested_sources(
+        &self,
+        filetype: Option<&str>,
+        requested: Option<&[(String, Value)]>,
+    ) -> Vec<(String, Value)> {
+        let configured = self.sources(filetype);
+
+        let defaults = match self.timeout_ms(filetype) {
+            Some(timeout_ms) => {
+                Value::Map(vec![(Value::from("timeout_ms"), Value::from(timeout_ms))])
+            }
+            None => Value::Map(Vec::new()),
+        };
+
+        let enabled: Vec<(String, Value)> = match requested {
+            Some(requested) => requested
+                .iter()
+                .map(|(name, opts)| {
+                    let base = configured.get(name).cloned().unwrap_or(Value::Nil);
+                    (name.clone(), merge_options(&base, opts))
+                })
+                .collect(),
+            None => configured
+                .enabled()
+                .map(|(name, opts)| (name.clone(), opts.clone()))
+                .collect(),
+        };
+
+        enabled
+            .into_iter()
+            .map(|(name, opts)| (name, merge_options(&defaults, &opts)))
+            .collect()
+    }
+
+    /// The deadline for sources that don't have their own.
+    fn timeout_ms(&self, filetype: Option<&str>) -> Option<u64> {
+        filetype
+            .and_then(|filetype| self.filetypes.get(filetype))
+            .and_then(|config| config.timeout_ms)
+            .or(self.timeout_ms)
+    }
+
+    pub fn merge_policy(&self, filetype: Option<&str>) -> MergePolicy {
+        let max_items = filetype
+            .and_then(|filetype| self.filetypes.get(filetype))
+            .and_then(|config| config.max_items)
+            .or(self.max_items);
+
+        match max_items {
+            Some(max_items) => MergePolicy { max_items },
+            None => MergePolicy::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::{BufferCompletionSource, FileCompletionSource};
+    use crate::testing::map;
+
+    const EXAMPLE: &str = r#"
+        log_level = "info"
+        max_items = 50
+        timeout_ms = 200
+
+        [sources]
+        buffer = { scope = "visible" }
+        file = { timeout_ms = 500 }
+
+        [filetypes.rust]
+        max_items = 20
+        timeout_ms = 300
+
+        [filetypes.rust.sources]
+        buffer = { min_length = 3 }
+        file = false
+    "#;
+
+    fn registry() -> SourceRegistry {
+        let mut registry = SourceRegistry::default();
+        registry.register(FileCompletionSource::default());
+        registry.register(BufferCompletionSource::default());
+        registry
+    }
+
+    #[test]
+    fn test_toml() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.validate(&registry()).unwrap();
+
+        assert_eq!(Some(LevelFilter::Info), config.log_level().unwrap());
+        assert_eq!(50, config.merge_policy(None).max_items);
+        assert_eq!(20, config.merge_policy(Some("rust")).max_items);
+        assert_eq!(50, config.merge_policy(Some("lua")).max_items);
+
+        // Sources get the global deadline, unless they have their own
+        assert_eq!(
+            vec![
+                (
+                    String::from("buffer"),
+                    map(vec![
+                        ("timeout_ms", Value::from(200)),
+                        ("scope", Value::from("visible"))
+                    ])
+                ),
+                (
+                    String::from("file"),
+                    map(vec![("timeout_ms", Value::from(500))])
+                ),
+            ],
+            config.requested_sources(None, None)
+        );
+
+        // Merged on top of the global ones, and `file` is turned off
+        assert_eq!(
+            vec![(
+                String::from("buffer"),
+                map(vec![
+                    ("timeout_ms", Value::from(300)),
+                    ("scope", Value::from("visible")),
+                    ("min_length", Value::from(3))
+                ])
+            )],
+            config.requested_sources(Some("rust"), None)
+        );
+    }
+
+    #[test]
+    fn test_msgpack_is_the_same_shape() {
+        let value = map(vec![
+            ("max_items", Value::from(50)),
+            ("timeout_ms", Value::from(200)),
+            (
+                "sources",
+                map(vec![
+                    ("buffer", map(vec![("scope", Value::from("visible"))])),
+                    ("file", map(vec![("timeout_ms", Value::from(500))])),
+                ]),
+            ),
+            (
+                "filetypes",
+                map(vec![(
+                    "rust",
+                    map(vec![
+                        ("max_items", Value::from(20)),
+                        ("timeout_ms", Value::from(300)),
+                        (
+                            "sources",
+                            map(vec![
+                                ("buffer", map(vec![("min_length", Value::from(3))])),
+                                ("file", Value::from(false)),
+                            ]),
+                        ),
+                    ]),
+                )]),
+            ),
+        ]);
+
+        let from_msgpack: Config = rmpv::ext::from_value(value).unwrap();
+        let from_toml = Config {
+            log_level: None,
+            ..toml::from_str(EXAMPLE).unwrap()
+        };
+        assert_eq!(from_toml, from_msgpack);
+    }
+
+    #[test]
+    fn test_empty_lua_tables() {
+        let config: Config = rmpv::ext::from_value(Value::Array(vec![])).unwrap();
+        assert_eq!(Config::default(), config);
+
+        let config: Config = rmpv::ext::from_value(map(vec![
+            ("sources", Value::Array(vec![])),
+            ("filetypes", Value::Array(vec![])),
+        ]))
+        .unwrap();
+        assert_eq!(Some(Sources::default()), config.sources);
+        assert!(config.filetypes.is_empty());
+    }
+
+    #[test]
+    fn test_request_options_go_on_top() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        let requested = vec![(
+            String::from("buffer"),
+            map(vec![("scope", Value::from("all"))]),
+        )];
+
+        assert_eq!(
+            vec![(
+                String::from("buffer"),
+                map(vec![
+                    ("timeout_ms", Value::from(300)),
+                    ("scope", Value::from("all")),
+                    ("min_length", Value::from(3))
+                ])
+            )],
+            config.requested_sources(Some("rust"), Some(&requested))
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::default();
+        assert_eq!(MergePolicy::default(), config.merge_policy(None));
+
+        let names: Vec<String> = config
+            .requested_sources(None, None)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(vec!["buffer", "file"], names);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = |config: &str| {
+            let config: Config = toml::from_str(config).unwrap();
+            config.validate(&registry()).is_err()
+        };
+
+        assert!(invalid("log_level = \"loud\""));
+        assert!(invalid("[sources]\nbuffer = { scope = \"nowhere\" }"));
+        assert!(invalid("[sources]\nrandom = true"));
+        assert!(invalid("[filetypes.rust.sources]\nfile = { hiden = true }"));
+
+        assert!(toml::from_str::<Config>("max_itmes = 3").is_err());
+        assert!(toml::from_str::<Config>("[sources]\nbuffer = 3").is_err());
+    }
+}
@@ -0,0 +1,602 @@
+// Our own copy of a buffer's text.
+//
+// Neovim tells us about every change through `nvim_buf_attach`, either as whole
+// lines (`on_lines`) or as byte ranges (`on_bytes`). We apply those here, and
+// hand the lines that actually changed to the sources, so they only have to
+// re-tokenize the part of the buffer that was touched.
+//
+// One change can come as several callbacks with the same `changedtick` (`:s///g`
+// sends an `on_bytes` for every match), so Lua numbers every callback it forwards
+// for a buffer, one after the other, and that number is what we go by.
+// Notifications are handled concurrently, so they don't always show up in order:
+// ones from the future wait in `pending` until the gap is filled, and ones from
+// the past are already part of the text. If we end up too far behind, or an edit
+// doesn't fit the text we have, we ask for the whole buffer, which comes with the
+// number of the last callback it includes.
+//
+// Applying a change and handing it to the sources happen under the same lock on
+// the document, so the sources see the changes in the order they were applied.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+
+/// How many updates can wait on a missing one before we give up on it.
+const MAX_PENDING: usize = 64;
+
+/// An edit reported by `on_bytes`.
+///
+/// The end positions are relative to the start, the same way Neovim sends them:
+/// when `old_end_row` is 0, `old_end_col` counts from `start_col`, otherwise it's
+/// the column on the last line. Same for `new_end_row` and `new_end_col`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ByteEdit {
+    pub start_row: u64,
+    pub start_col: u64,
+
+    pub old_end_row: u64,
+    pub old_end_col: u64,
+
+    pub new_end_row: u64,
+    pub new_end_col: u64,
+
+    /// The text that's now between start and new end, split into lines
+    pub text: Vec<String>,
+}
+
+/// Lines `start..old_end` were replaced by `lines`, which is what sources get in `on_lines`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineChange {
+    pub start: u64,
+    pub old_end: u64,
+    pub lines: Vec<String>,
+}
+
+/// One `nvim_buf_attach` callback.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    /// From `on_lines`: lines `start..old_end` became `lines`
+    Lines {
+        start: u64,
+        old_end: u64,
+        lines: Vec<String>,
+    },
+
+    /// From `on_bytes`
+    Bytes(ByteEdit),
+
+    /// From `on_changedtick`: the tick went up, but the text didn't change
+    Tick,
+}
+
+/// What an update or a sync did to a `Document`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Applied {
+    /// The lines that changed, in the order they did. These have happened even if
+    /// `needs_sync` is set, so the sources still need to hear about them.
+    pub changes: Vec<LineChange>,
+
+    /// The document can't be trusted anymore, and has to be synced with the whole buffer
+    pub needs_sync: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Document {
+    lines: Vec<String>,
+
+    /// Lua's number for the last update in `lines`, or `None` if we've never had the whole buffer
+    seq: Option<u64>,
+
+    /// Updates that arrived before the ones they come after, by their number
+    pending: BTreeMap<u64, Update>,
+
+    /// Whether we've already asked for the whole buffer
+    syncing: bool,
+}
+
+impl Document {
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Whether `lines` is the buffer as it is, as far as we know.
+    pub fn is_synced(&self) -> bool {
+        self.seq.is_some() && !self.syncing
+    }
+
+    /// Apply update number `seq`, along with any pending ones it unblocks.
+    ///
+    /// Gives back the lines that changed, and says whether we need a sync the first
+    /// time we notice we're out of it. Until `sync` is called after that, updates are
+    /// only queued.
+    pub fn update(&mut self, seq: u64, update: Update) -> Applied {
+        if self.seq.is_some_and(|current| seq <= current) {
+            // Already in the text we have
+            return Applied::default();
+        }
+
+        if self.pending.insert(seq, update).is_some() || self.pending.len() > MAX_PENDING {
+            return self.needs_sync(Vec::new());
+        }
+
+        match self.seq {
+            Some(_) if !self.syncing => self.apply_pending(Vec::new()),
+            _ => self.needs_sync(Vec::new()),
+        }
+    }
+
+    /// Replace everything with the whole buffer as of update number `seq`.
+    ///
+    /// The first change replaces every line the sources might have, however many
+    /// that is, followed by any pending updates that came after it. A buffer older
+    /// than what we have already is ignored, since two syncs can be answered in
+    /// either order.
+    pub fn sync(&mut self, seq: u64, lines: Vec<String>) -> Applied {
+        if self.seq.is_some_and(|current| current >= seq) {
+            return Applied::default();
+        }
+
+        self.lines = lines.clone();
+        self.seq = Some(seq);
+        self.syncing = false;
+        self.pending.retain(|&pending, _| pending > seq);
+
+        self.apply_pending(vec![LineChange {
+            start: 0,
+            old_end: u64::MAX,
+            lines,
+        }])
+    }
+
+    /// Getting the whole buffer didn't work out, so the next update should ask again.
+    pub fn sync_failed(&mut self) {
+        self.syncing = false;
+    }
+
+    fn needs_sync(&mut self, changes: Vec<LineChange>) -> Applied {
+        let needs_sync = !self.syncing;
+        self.syncing = true;
+
+        Applied {
+            changes,
+            needs_sync,
+        }
+    }
+
+    /// Apply pending updates for as long as they follow on from each other, after `changes`.
+    fn apply_pending(&mut self, mut changes: Vec<LineChange>) -> Applied {
+        while let Some(current) = self.seq {
+            let update = match self.pending.remove(&(current + 1)) {
+                Some(update) => update,
+                None => break,
+            };
+
+            match update {
+                Update::Lines {
+                    start,
+                    old_end,
+                    lines,
+                } => {
+                    self.splice_lines(start, old_end, &lines);
+                    changes.push(LineChange {
+                        start,
+                        old_end,
+                        lines,
+                    });
+                }
+                Update::Bytes(edit) => match self.apply_bytes(&edit) {
+                    Ok(change) => changes.push(change),
+                    Err(_) => return self.needs_sync(changes),
+                },
+                Update::Tick => {}
+            }
+
+            self.seq = Some(current + 1);
+        }
+
+        Applied {
+            changes,
+            needs_sync: false,
+        }
+    }
+
+    /// Apply an `on_lines` style change: lines `start..old_end` become `lines`.
+    pub fn splice_lines(&mut self, start: u64, old_end: u64, lines: &[String]) {
+        let start = start as usize;
+        if start > self.lines.len() {
+            self.lines.resize(start, String::new());
+        }
+
+        let old_end = (old_end as usize).clamp(start, self.lines.len());
+        self.lines.splice(start..old_end, lines.iter().cloned());
+    }
+
+    /// Apply an `on_bytes` edit, and report which whole lines it ended up changing.
+    ///
+    /// Fails if the edit doesn't line up with the text we have, which means we
+    /// missed something and need to get the whole buffer again.
+    pub fn apply_bytes(&mut self, edit: &ByteEdit) -> Result<LineChange> {
+        let start_row = edit.start_row as usize;
+        let start_col = edit.start_col as usize;
+
+        let old_end_row = start_row + edit.old_end_row as usize;
+        let old_end_col = if edit.old_end_row == 0 {
+            start_col + edit.old_end_col as usize
+        } else {
+            edit.old_end_col as usize
+        };
+
+        if start_row > self.lines.len() {
+            return Err(anyhow!(
+                "edit starts at line {}, but there are only {} lines",
+                start_row,
+                self.lines.len()
+            ));
+        }
+
+        // Rows past the end are the "line" after the final newline, which is always empty
+        let line_or_empty = |row: usize| self.lines.get(row).map_or("", |line| line.as_str());
+
+        let start_line = line_or_empty(start_row);
+        let end_line = line_or_empty(old_end_row);
+
+        let prefix = start_line
+            .get(..start_col)
+            .ok_or_else(|| anyhow!("start column {} is not in {:?}", start_col, start_line))?;
+        let suffix = end_line
+            .get(old_end_col..)
+            .ok_or_else(|| anyhow!("end column {} is not in {:?}", old_end_col, end_line))?;
+
+        let text = edit.text.join("\n");
+        let mut new_lines: Vec<String> = format!("{}{}{}", prefix, text, suffix)
+            .split('\n')
+            .map(String::from)
+            .collect();
+
+        let replaced_end = (old_end_row + 1).min(self.lines.len());
+
+        // Writing into the line after the final newline adds a newline of its own,
+        // which shouldn't turn into an extra empty line.
+        if old_end_row >= self.lines.len()
+            && new_lines.len() > 1
+            && new_lines.last().is_some_and(|line| line.is_empty())
+        {
+            new_lines.pop();
+        }
+
+        self.lines
+            .splice(start_row..replaced_end, new_lines.iter().cloned());
+
+        Ok(LineChange {
+            start: start_row as u64,
+            old_end: replaced_end as u64,
+            lines: new_lines,
+        })
+    }
+}
+
+/// Every attached buffer's `Document`, shared between the handler and anything that wants the text.
+#[derive(Debug, Default)]
+pub struct DocumentStore {
+    documents: RwLock<HashMap<u64, Arc<Mutex<Document>>>>,
+}
+
+impl DocumentStore {
+    /// A buffer's document, to change, starting it off empty if we don't have one.
+    ///
+    /// Keep hold of it until the sources have been told what changed, or another
+    /// update can get to them first.
+    pub async fn lock(&self, bufnr: u64) -> OwnedMutexGuard<Document> {
+        let document = self
+            .documents
+            .write()
+            .await
+            .entry(bufnr)
+            .or_default()
+            .clone();
+
+        document.lock_owned().await
+    }
+
+    pub async fn sync_failed(&self, bufnr: u64) {
+        let document = self.documents.read().await.get(&bufnr).cloned();
+        if let Some(document) = document {
+            document.lock().await.sync_failed();
+        }
+    }
+
+    pub async fn remove(&self, bufnr: u64) {
+        self.documents.write().await.remove(&bufnr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(lines: &[&str]) -> Document {
+        let mut document = Document::default();
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        document.splice_lines(0, 0, &lines);
+        document
+    }
+
+    fn edit(
+        start: (u64, u64),
+        old_end: (u64, u64),
+        new_end: (u64, u64),
+        text: &[&str],
+    ) -> ByteEdit {
+        ByteEdit {
+            start_row: start.0,
+            start_col: start.1,
+            old_end_row: old_end.0,
+            old_end_col: old_end.1,
+            new_end_row: new_end.0,
+            new_end_col: new_end.1,
+            text: text.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_insert_a_character() {
+        let mut doc = document(&["hello world", "second"]);
+
+        let change = doc
+            .apply_bytes(&edit((0, 5), (0, 0), (0, 1), &["!"]))
+            .unwrap();
+
+        assert_eq!(vec!["hello! world", "second"], doc.lines());
+        assert_eq!(
+            LineChange {
+                start: 0,
+                old_end: 1,
+                lines: vec![String::from("hello! world")]
+            },
+            change
+        );
+    }
+
+    #[test]
+    fn test_delete_across_lines() {
+        let mut doc = document(&["hello", "big", "world"]);
+
+        // Join everything from the `l` in hello to the `r` in world
+        let change = doc
+            .apply_bytes(&edit((0, 3), (2, 2), (0, 0), &[""]))
+            .unwrap();
+
+        assert_eq!(vec!["helrld"], doc.lines());
+        assert_eq!(0, change.start);
+        assert_eq!(3, change.old_end);
+    }
+
+    #[test]
+    fn test_open_a_new_line() {
+        let mut doc = document(&["hello", "world"]);
+
+        // `o` on the first line
+        let change = doc
+            .apply_bytes(&edit((0, 5), (0, 0), (1, 0), &["", ""]))
+            .unwrap();
+
+        assert_eq!(vec!["hello", "", "world"], doc.lines());
+        assert_eq!(2, change.lines.len());
+    }
+
+    #[test]
+    fn test_delete_the_last_line() {
+        let mut doc = document(&["hello", "world"]);
+
+        // `dd` on the last line removes the newline before it
+        doc.apply_bytes(&edit((0, 5), (1, 5), (0, 0), &[""]))
+            .unwrap();
+
+        assert_eq!(vec!["hello"], doc.lines());
+    }
+
+    #[test]
+    fn test_append_after_the_last_line() {
+        let mut doc = document(&["hello"]);
+
+        // `:$put` adds a whole line after the final newline
+        doc.apply_bytes(&edit((1, 0), (0, 0), (1, 0), &["world", ""]))
+            .unwrap();
+
+        assert_eq!(vec!["hello", "world"], doc.lines());
+    }
+
+    #[test]
+    fn test_multibyte() {
+        let mut doc = document(&["héllo"]);
+
+        // `é` is two bytes, so `l` starts at byte 3
+        doc.apply_bytes(&edit((0, 3), (0, 2), (0, 1), &["L"]))
+            .unwrap();
+
+        assert_eq!(vec!["héLo"], doc.lines());
+    }
+
+    #[test]
+    fn test_edits_that_dont_line_up_fail() {
+        let mut doc = document(&["héllo"]);
+
+        // In the middle of `é`
+        assert!(doc
+            .apply_bytes(&edit((0, 2), (0, 0), (0, 1), &["x"]))
+            .is_err());
+
+        // Way past the end
+        assert!(doc
+            .apply_bytes(&edit((5, 0), (0, 0), (0, 1), &["x"]))
+            .is_err());
+        assert!(doc
+            .apply_bytes(&edit((0, 50), (0, 0), (0, 1), &["x"]))
+            .is_err());
+
+        assert_eq!(vec!["héllo"], doc.lines());
+    }
+
+    fn synced(lines: &[&str], seq: u64) -> Document {
+        let mut doc = Document::default();
+        let lines = lines.iter().map(|line| line.to_string()).collect();
+        assert!(!doc.sync(seq, lines).needs_sync);
+        doc
+    }
+
+    fn insert(row: u64, col: u64, text: &str) -> Update {
+        Update::Bytes(edit((row, col), (0, 0), (0, text.len() as u64), &[text]))
+    }
+
+    /// Replace as many characters as there are in `text`.
+    fn replace(row: u64, col: u64, text: &str) -> Update {
+        let len = text.len() as u64;
+        Update::Bytes(edit((row, col), (0, len), (0, len), &[text]))
+    }
+
+    fn needs_sync() -> Applied {
+        Applied {
+            changes: Vec::new(),
+            needs_sync: true,
+        }
+    }
+
+    #[test]
+    fn test_needs_sync_before_anything_else() {
+        let mut doc = Document::default();
+
+        assert_eq!(needs_sync(), doc.update(5, insert(0, 0, "a")));
+        // Only ask once
+        assert_eq!(Applied::default(), doc.update(6, insert(0, 1, "b")));
+
+        // The first insert is already in the buffer we got back, the second isn't
+        let applied = doc.sync(5, vec![String::from("a")]);
+        assert_eq!(2, applied.changes.len());
+        assert_eq!(u64::MAX, applied.changes[0].old_end);
+        assert_eq!(vec!["ab"], doc.lines());
+        assert_eq!(Some(6), doc.seq);
+    }
+
+    #[test]
+    fn test_older_sync_is_ignored() {
+        // `buf_initialize` and the first `on_bytes` both ask for the buffer
+        let mut doc = synced(&["new"], 5);
+        assert_eq!(Applied::default(), doc.update(7, insert(0, 4, "!")));
+
+        assert_eq!(Applied::default(), doc.sync(3, vec![String::from("old")]));
+        assert_eq!(vec!["new"], doc.lines());
+        assert_eq!(Some(5), doc.seq);
+
+        // What came after the newer one still applies
+        assert_eq!(2, doc.update(6, insert(0, 0, "!")).changes.len());
+        assert_eq!(vec!["!new!"], doc.lines());
+    }
+
+    #[test]
+    fn test_updates_out_of_order() {
+        let mut doc = synced(&["x"], 1);
+
+        assert_eq!(Applied::default(), doc.update(3, insert(0, 2, "z")));
+        assert_eq!(vec!["x"], doc.lines());
+
+        let applied = doc.update(2, insert(0, 1, "y"));
+        assert_eq!(2, applied.changes.len());
+        assert_eq!(vec!["xyz"], doc.lines());
+
+        // Old news
+        assert_eq!(Applied::default(), doc.update(3, insert(0, 0, "!")));
+        assert_eq!(vec!["xyz"], doc.lines());
+    }
+
+    #[test]
+    fn test_several_edits_in_one_change() {
+        // `:s/x/y/g` sends an `on_bytes` for each match, all with the same changedtick
+        let mut doc = synced(&["x x x"], 1);
+
+        assert_eq!(1, doc.update(2, replace(0, 0, "y")).changes.len());
+        assert_eq!(Applied::default(), doc.update(4, replace(0, 4, "y")));
+        assert_eq!(2, doc.update(3, replace(0, 2, "y")).changes.len());
+
+        assert_eq!(vec!["y y y"], doc.lines());
+        assert_eq!(Some(4), doc.seq);
+        assert!(!doc.syncing);
+    }
+
+    #[test]
+    fn test_changedtick_without_changes() {
+        let mut doc = synced(&["x"], 1);
+
+        assert_eq!(Applied::default(), doc.update(3, insert(0, 1, "y")));
+        assert_eq!(1, doc.update(2, Update::Tick).changes.len());
+        assert_eq!(vec!["xy"], doc.lines());
+    }
+
+    #[test]
+    fn test_missed_updates_need_sync() {
+        let mut doc = synced(&["x"], 1);
+
+        // Update 2 never shows up
+        let mut applied = Applied::default();
+        for seq in 3..(3 + MAX_PENDING as u64 + 1) {
+            applied = doc.update(seq, Update::Tick);
+        }
+        assert_eq!(needs_sync(), applied);
+    }
+
+    #[test]
+    fn test_bad_edits_need_sync() {
+        let mut doc = synced(&["x"], 1);
+
+        assert_eq!(needs_sync(), doc.update(2, insert(0, 10, "y")));
+
+        assert!(!doc.sync(2, vec![String::from("xy")]).needs_sync);
+        assert!(!doc.update(3, insert(0, 2, "z")).needs_sync);
+        assert_eq!(vec!["xyz"], doc.lines());
+    }
+
+    #[test]
+    fn test_changes_before_a_bad_edit_are_kept() {
+        let mut doc = synced(&["x"], 1);
+        doc.update(3, insert(0, 10, "z"));
+
+        // 2 fits, 3 doesn't, and the sources still need to hear about 2
+        let applied = doc.update(2, insert(0, 1, "y"));
+        assert!(applied.needs_sync);
+        assert_eq!(1, applied.changes.len());
+        assert_eq!(vec!["xy"], applied.changes[0].lines);
+        assert_eq!(vec!["xy"], doc.lines());
+
+        // Same goes for the lines a sync brings in
+        let applied = doc.sync(2, vec![String::from("xy")]);
+        assert!(!applied.needs_sync);
+        doc.update(4, insert(0, 10, "!"));
+        let applied = doc.sync(3, vec![String::from("xyz")]);
+        assert!(applied.needs_sync);
+        assert_eq!(1, applied.changes.len());
+        assert_eq!(vec!["xyz"], doc.lines());
+    }
+
+    #[tokio::test]
+    async fn test_lock_holds_off_other_updates() {
+        let store = Arc::new(DocumentStore::default());
+        let mut first = store.lock(1).await;
+        assert!(!first.sync(1, vec![String::from("x")]).needs_sync);
+
+        let second = {
+            let store = store.clone();
+            tokio::spawn(async move { store.lock(1).await.update(2, insert(0, 1, "y")) })
+        };
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+
+        // Not until the sources have heard about the first one
+        assert_eq!(vec!["x"], first.lines());
+        drop(first);
+
+        assert_eq!(1, second.await.unwrap().changes.len());
+        assert_eq!(vec!["xy"], store.lock(1).await.lines());
+    }
+}
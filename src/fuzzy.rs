@@ -0,0 +1,197 @@
+// Fuzzy matching shared by all of the sources.
+//
+// The pattern has to be a subsequence of the candidate, and the first character
+// of the pattern has to match the first character of the candidate. Otherwise
+// typing `w` would bring up every word with a `w` somewhere in it.
+//
+// Scoring is loosely based on fzy: every matched character is worth something,
+// and it's worth more if it starts a word (`hw` -> `hello_world`), is a camelCase
+// hump (`gFM` -> `getFuzzyMatches`), or directly follows the previous match.
+// Skipping characters between matches costs a little.
+
+const SCORE_MATCH: i64 = 16;
+
+const BONUS_BOUNDARY: i64 = 30;
+const BONUS_CAMEL: i64 = 24;
+const BONUS_CONSECUTIVE: i64 = 16;
+const BONUS_EXACT_CASE: i64 = 1;
+
+const PENALTY_GAP: i64 = 2;
+
+/// Smart case: lowercase characters match either case, uppercase ones only match themselves.
+fn chars_match(pattern: char, candidate: char) -> bool {
+    if pattern.is_uppercase() {
+        pattern == candidate
+    } else {
+        pattern == candidate || candidate.to_lowercase().eq(pattern.to_lowercase())
+    }
+}
+
+/// The prefixes a candidate has to start with to possibly match `pattern`.
+///
+/// Since the first character has to match, this lets callers with a sorted index
+/// (like the buffer source's `Trie`) skip everything else. An empty pattern gives
+/// back the empty prefix, which matches everything.
+pub fn first_char_prefixes(pattern: &str) -> Vec<String> {
+    let first = match pattern.chars().next() {
+        Some(first) => first,
+        None => return vec![String::new()],
+    };
+
+    let mut prefixes = vec![first.to_string()];
+    if !first.is_uppercase() {
+        let upper: String = first.to_uppercase().collect();
+        if upper != prefixes[0] {
+            prefixes.push(upper);
+        }
+    }
+
+    prefixes
+}
+
+/// How good of a place `candidate[index]` is to match a pattern character.
+fn position_bonus(candidate: &[char], index: usize) -> i64 {
+    let current = candidate[index];
+    if index == 0 {
+        return BONUS_BOUNDARY;
+    }
+
+    let previous = candidate[index - 1];
+    if current.is_alphanumeric() && !previous.is_alphanumeric() {
+        BONUS_BOUNDARY
+    } else if (current.is_uppercase() && previous.is_lowercase())
+        || (current.is_numeric() && !previous.is_numeric())
+    {
+        BONUS_CAMEL
+    } else {
+        0
+    }
+}
+
+/// Score `candidate` against `pattern`. Higher is better, and `None` means it doesn't match.
+///
+/// An empty pattern matches everything with a score of 0.
+pub fn score(pattern: &str, candidate: &str) -> Option<i64> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let candidate: Vec<char> = candidate.chars().collect();
+
+    if pattern.is_empty() {
+        return Some(0);
+    }
+
+    if pattern.len() > candidate.len() || !chars_match(pattern[0], candidate[0]) {
+        return None;
+    }
+
+    let bonuses: Vec<i64> = (0..candidate.len())
+        .map(|index| position_bonus(&candidate, index))
+        .collect();
+
+    // matched[j]: best score with the current pattern char matched exactly at candidate[j]
+    // best[j]: best score with the current pattern char matched anywhere up to candidate[j],
+    //          minus the gap penalty for everything skipped since then
+    let mut matched: Vec<Option<i64>> = vec![None; candidate.len()];
+    let mut best: Vec<Option<i64>> = vec![None; candidate.len()];
+
+    for (i, &p) in pattern.iter().enumerate() {
+        let previous_matched = matched;
+        let previous_best = best;
+
+        matched = vec![None; candidate.len()];
+        best = vec![None; candidate.len()];
+
+        for j in i..candidate.len() {
+            let c = candidate[j];
+            if chars_match(p, c) && (i > 0 || j == 0) {
+                let mut char_score = SCORE_MATCH + bonuses[j];
+                if p == c {
+                    char_score += BONUS_EXACT_CASE;
+                }
+
+                matched[j] = if i == 0 {
+                    Some(char_score)
+                } else {
+                    let consecutive = previous_matched[j - 1].map(|s| s + BONUS_CONSECUTIVE);
+                    let gapped = previous_best[j - 1];
+
+                    consecutive.max(gapped).map(|s| s + char_score)
+                };
+            }
+
+            let carried = if j > 0 {
+                best[j - 1].map(|s| s - PENALTY_GAP)
+            } else {
+                None
+            };
+            best[j] = matched[j].max(carried);
+        }
+    }
+
+    matched.into_iter().flatten().max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_pattern_matches_everything() {
+        assert_eq!(Some(0), score("", "hello"));
+        assert_eq!(Some(0), score("", ""));
+    }
+
+    #[test]
+    fn test_subsequence_matches() {
+        assert!(score("hw", "hello_world").is_some());
+        assert!(score("hlo", "hello").is_some());
+        assert!(score("hello", "hello").is_some());
+
+        assert_eq!(None, score("wh", "hello_world"));
+        assert_eq!(None, score("hellos", "hello"));
+    }
+
+    #[test]
+    fn test_first_char_has_to_match() {
+        assert_eq!(None, score("w", "hello_world"));
+        assert_eq!(None, score("ello", "hello"));
+    }
+
+    #[test]
+    fn test_first_char_prefixes() {
+        assert_eq!(vec![String::new()], first_char_prefixes(""));
+        assert_eq!(vec!["h", "H"], first_char_prefixes("hw"));
+        assert_eq!(vec!["H"], first_char_prefixes("Hw"));
+        assert_eq!(vec!["_"], first_char_prefixes("_x"));
+    }
+
+    #[test]
+    fn test_smart_case() {
+        assert!(score("car", "Cargo.toml").is_some());
+        assert!(score("Car", "Cargo.toml").is_some());
+        assert_eq!(None, score("Car", "cargo.toml"));
+    }
+
+    #[test]
+    fn test_word_boundaries_beat_the_middle_of_words() {
+        assert!(score("hw", "hello_world").unwrap() > score("hw", "hellow").unwrap());
+    }
+
+    #[test]
+    fn test_camel_humps() {
+        let hump = score("gfm", "getFuzzyMatches").unwrap();
+        let no_hump = score("gfm", "getfuzzymatches").unwrap();
+        assert!(hump > no_hump);
+    }
+
+    #[test]
+    fn test_consecutive_runs() {
+        assert!(score("hel", "hello").unwrap() > score("hel", "hxexl").unwrap());
+    }
+
+    #[test]
+    fn test_picks_the_best_alignment() {
+        // Matching the second `w` (at a word boundary) beats the first one,
+        // even though the first one would be a consecutive run.
+        assert!(score("hw", "hwx_w").unwrap() > score("hw", "hwxxx").unwrap());
+    }
+}
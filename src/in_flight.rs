@@ -0,0 +1,116 @@
+// Completion requests we're still working on.
+//
+// Lua numbers its completion requests, and every new one makes the ones before
+// it pointless, since they're for text that has already changed. So starting a
+// request aborts anything older that's still running, and `cancel` aborts
+// everything up to the id it names. Requests that only show up after they've
+// been superseded (notifications can be handled out of order) never start.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use futures::future::{AbortHandle, AbortRegistration};
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: HashMap<u64, AbortHandle>,
+
+    /// Anything with a lower id than this is stale
+    stale_below: u64,
+}
+
+impl State {
+    /// Abort everything below `id`, and keep it from starting later.
+    fn supersede(&mut self, id: u64) {
+        self.stale_below = self.stale_below.max(id);
+        self.running.retain(|&running, handle| {
+            if running < id {
+                handle.abort();
+            }
+            running >= id
+        });
+    }
+}
+
+impl InFlight {
+    /// Start request `id`, superseding the older ones. `None` if it's already stale.
+    pub fn start(&self, id: u64) -> Option<AbortRegistration> {
+        let mut state = self.state.lock().expect("in flight lock");
+        if id < state.stale_below {
+            return None;
+        }
+
+        state.supersede(id);
+
+        let (handle, registration) = AbortHandle::new_pair();
+        if let Some(previous) = state.running.insert(id, handle) {
+            // Lua sent the same id twice, the newer one wins
+            previous.abort();
+        }
+
+        Some(registration)
+    }
+
+    /// Abort request `id` and everything before it.
+    pub fn cancel(&self, id: u64) {
+        self.state
+            .lock()
+            .expect("in flight lock")
+            .supersede(id.saturating_add(1));
+    }
+
+    /// Request `id` is done. Returns whether its results are still wanted.
+    pub fn finish(&self, id: u64) -> bool {
+        let mut state = self.state.lock().expect("in flight lock");
+        state.running.remove(&id);
+        id >= state.stale_below
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{self, Abortable};
+
+    #[tokio::test]
+    async fn test_newer_request_aborts_older() {
+        let in_flight = InFlight::default();
+
+        let first = Abortable::new(future::pending::<()>(), in_flight.start(1).unwrap());
+        let second = in_flight.start(2).unwrap();
+
+        assert!(first.await.is_err());
+        assert!(!in_flight.finish(1));
+
+        assert_eq!(Ok(2), Abortable::new(future::ready(2), second).await);
+        assert!(in_flight.finish(2));
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let in_flight = InFlight::default();
+
+        let first = Abortable::new(future::pending::<()>(), in_flight.start(1).unwrap());
+        in_flight.cancel(1);
+        assert!(first.await.is_err());
+
+        // Cancelled before it got here
+        in_flight.cancel(3);
+        assert!(in_flight.start(3).is_none());
+        assert!(in_flight.start(2).is_none());
+        assert!(in_flight.start(4).is_some());
+    }
+
+    #[test]
+    fn test_late_request_never_starts() {
+        let in_flight = InFlight::default();
+
+        assert!(in_flight.start(5).is_some());
+        assert!(in_flight.start(4).is_none());
+        assert!(in_flight.finish(5));
+    }
+}
@@ -0,0 +1,8 @@
+// The pieces of rofl that don't need a running Neovim, so they can be benchmarked.
+//
+// Everything else lives in the binary, see `main.rs`.
+
+pub mod collections;
+pub mod fuzzy;
+pub mod nvim;
+pub mod word_store;
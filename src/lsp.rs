@@ -0,0 +1,625 @@
+// Rust versions of the LSP completion types.
+//
+// See `doc/lsp_completion_item.ts` for the TypeScript definitions these come from.
+//
+// These get decoded straight from whatever the Lua side forwards to us from
+// `textDocument/completion`, and then turned into our own `CompletionItem` so
+// they can go through the same pipeline as the buffer and file sources.
+
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use nvim_rs::Value;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::sources::CompletionItem;
+
+/// The kind of a completion entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum CompletionItemKind {
+    Text = 1,
+    Method = 2,
+    Function = 3,
+    Constructor = 4,
+    Field = 5,
+    Variable = 6,
+    Class = 7,
+    Interface = 8,
+    Module = 9,
+    Property = 10,
+    Unit = 11,
+    Value = 12,
+    Enum = 13,
+    Keyword = 14,
+    Snippet = 15,
+    Color = 16,
+    File = 17,
+    Reference = 18,
+    Folder = 19,
+    EnumMember = 20,
+    Constant = 21,
+    Struct = 22,
+    Event = 23,
+    Operator = 24,
+    TypeParameter = 25,
+}
+
+impl CompletionItemKind {
+    pub const ALL: [CompletionItemKind; 25] = [
+        CompletionItemKind::Text,
+        CompletionItemKind::Method,
+        CompletionItemKind::Function,
+        CompletionItemKind::Constructor,
+        CompletionItemKind::Field,
+        CompletionItemKind::Variable,
+        CompletionItemKind::Class,
+        CompletionItemKind::Interface,
+        CompletionItemKind::Module,
+        CompletionItemKind::Property,
+        CompletionItemKind::Unit,
+        CompletionItemKind::Value,
+        CompletionItemKind::Enum,
+        CompletionItemKind::Keyword,
+        CompletionItemKind::Snippet,
+        CompletionItemKind::Color,
+        CompletionItemKind::File,
+        CompletionItemKind::Reference,
+        CompletionItemKind::Folder,
+        CompletionItemKind::EnumMember,
+        CompletionItemKind::Constant,
+        CompletionItemKind::Struct,
+        CompletionItemKind::Event,
+        CompletionItemKind::Operator,
+        CompletionItemKind::TypeParameter,
+    ];
+
+    /// The single letter Vim shows in the `kind` column of the popup menu.
+    ///
+    /// Vim only documents v, f, m, t and d, so anything that doesn't fit one of those
+    /// gets a letter that still reads well.
+    pub fn vim_kind(self) -> &'static str {
+        use CompletionItemKind::*;
+
+        match self {
+            Method | Function | Constructor | Operator => "f",
+            Field | Property | EnumMember | Event => "m",
+            Variable | Value | Constant | Reference => "v",
+            Class | Interface | Enum | Struct | TypeParameter | Unit => "t",
+            Module => "M",
+            Keyword => "k",
+            Snippet => "s",
+            Color => "c",
+            File | Folder => "F",
+            Text => "w",
+        }
+    }
+}
+
+impl CompletionItemKind {
+    /// Looks up a kind by a friendlier name, the way you'd write it in a Lua config.
+    ///
+    /// Case and underscores don't matter, and plurals work too,
+    /// so `'functions'`, `'Function'` and `'enum_members'` are all fine.
+    pub fn from_name(name: &str) -> Option<CompletionItemKind> {
+        let name = name.to_lowercase().replace('_', "");
+
+        CompletionItemKind::ALL.iter().copied().find(|kind| {
+            let kind_name = format!("{:?}", kind).to_lowercase();
+            let plural = if let Some(stem) = kind_name.strip_suffix('y') {
+                format!("{}ies", stem)
+            } else if kind_name.ends_with('s') {
+                format!("{}es", kind_name)
+            } else {
+                format!("{}s", kind_name)
+            };
+
+            name == kind_name || name == plural
+        })
+    }
+}
+
+impl TryFrom<u8> for CompletionItemKind {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        CompletionItemKind::ALL
+            .iter()
+            .find(|kind| **kind as u8 == value)
+            .copied()
+            .ok_or_else(|| format!("Unknown CompletionItemKind: {}", value))
+    }
+}
+
+impl From<CompletionItemKind> for u8 {
+    fn from(kind: CompletionItemKind) -> Self {
+        kind as u8
+    }
+}
+
+/// Defines whether the insert text in a completion item should be interpreted as
+/// plain text or a snippet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum InsertTextFormat {
+    PlainText = 1,
+    Snippet = 2,
+}
+
+impl TryFrom<u8> for InsertTextFormat {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(InsertTextFormat::PlainText),
+            2 => Ok(InsertTextFormat::Snippet),
+            _ => Err(format!("Unknown InsertTextFormat: {}", value)),
+        }
+    }
+}
+
+impl From<InsertTextFormat> for u8 {
+    fn from(format: InsertTextFormat) -> Self {
+        format as u8
+    }
+}
+
+/// Extra annotations that tweak the rendering of a completion item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum CompletionItemTag {
+    Deprecated = 1,
+}
+
+impl TryFrom<u8> for CompletionItemTag {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(CompletionItemTag::Deprecated),
+            _ => Err(format!("Unknown CompletionItemTag: {}", value)),
+        }
+    }
+}
+
+impl From<CompletionItemTag> for u8 {
+    fn from(tag: CompletionItemTag) -> Self {
+        tag as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Position {
+    pub line: u64,
+    pub character: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextEdit {
+    pub range: Range,
+    pub new_text: String,
+}
+
+/// A special text edit to provide an insert and a replace operation.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertReplaceEdit {
+    pub new_text: String,
+    pub insert: Range,
+    pub replace: Range,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum CompletionTextEdit {
+    Edit(TextEdit),
+    InsertReplace(InsertReplaceEdit),
+}
+
+impl CompletionTextEdit {
+    pub fn new_text(&self) -> &str {
+        match self {
+            CompletionTextEdit::Edit(edit) => &edit.new_text,
+            CompletionTextEdit::InsertReplace(edit) => &edit.new_text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MarkupContent {
+    /// Either "plaintext" or "markdown"
+    pub kind: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Documentation {
+    String(String),
+    Markup(MarkupContent),
+}
+
+impl Documentation {
+    pub fn value(&self) -> &str {
+        match self {
+            Documentation::String(value) => value,
+            Documentation::Markup(markup) => &markup.value,
+        }
+    }
+}
+
+/// Servers send kinds and tags newer than the ones we know, which LSP says clients
+/// should handle, so those are left out instead of failing the whole response.
+fn known<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u8>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(value.as_ref().and_then(known_value))
+}
+
+fn known_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u8>,
+{
+    let values = Option::<Vec<Value>>::deserialize(deserializer)?;
+    Ok(values.map(|values| values.iter().filter_map(known_value).collect()))
+}
+
+fn known_value<T: TryFrom<u8>>(value: &Value) -> Option<T> {
+    let value = u8::try_from(value.as_u64()?).ok()?;
+    T::try_from(value).ok()
+}
+
+/// A completion item, as sent by a language server.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LspCompletionItem {
+    pub label: String,
+
+    #[serde(
+        default,
+        deserialize_with = "known",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub kind: Option<CompletionItemKind>,
+
+    #[serde(
+        default,
+        deserialize_with = "known_list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tags: Option<Vec<CompletionItemTag>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub documentation: Option<Documentation>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preselect: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_text: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter_text: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insert_text: Option<String>,
+
+    #[serde(
+        default,
+        deserialize_with = "known",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub insert_text_format: Option<InsertTextFormat>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_edit: Option<CompletionTextEdit>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_text_edits: Option<Vec<TextEdit>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_characters: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl LspCompletionItem {
+    /// The text to use when filtering this item. When `filterText` is missing, the label is used.
+    pub fn filter_text(&self) -> &str {
+        self.filter_text.as_deref().unwrap_or(&self.label)
+    }
+
+    /// The text to use when sorting this item. When `sortText` is missing, the label is used.
+    pub fn sort_text(&self) -> &str {
+        self.sort_text.as_deref().unwrap_or(&self.label)
+    }
+
+    /// The text that actually ends up in the buffer.
+    ///
+    /// We don't expand snippets (yet), so for those the label is the best we can do.
+    pub fn insert_word(&self) -> &str {
+        if self.insert_text_format == Some(InsertTextFormat::Snippet) {
+            return &self.label;
+        }
+
+        match &self.text_edit {
+            Some(text_edit) => text_edit.new_text(),
+            None => self.insert_text.as_deref().unwrap_or(&self.label),
+        }
+    }
+
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated.unwrap_or(false)
+            || self
+                .tags
+                .as_ref()
+                .is_some_and(|tags| tags.contains(&CompletionItemTag::Deprecated))
+    }
+}
+
+/// Represents a collection of completion items to be presented in the editor.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionList {
+    /// This list it not complete. Further typing should result in recomputing this list.
+    #[serde(default)]
+    pub is_incomplete: bool,
+
+    pub items: Vec<LspCompletionItem>,
+}
+
+/// `textDocument/completion` can answer with a list, a bare array of items, or nothing at all.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum CompletionResponse {
+    List(CompletionList),
+    Items(Vec<LspCompletionItem>),
+}
+
+impl CompletionList {
+    /// Decode a `textDocument/completion` result forwarded from Lua.
+    pub fn from_response(response: Value) -> Result<CompletionList> {
+        if response.is_nil() {
+            return Ok(CompletionList {
+                is_incomplete: false,
+                items: Vec::new(),
+            });
+        }
+
+        Ok(match rmpv::ext::from_value(response)? {
+            CompletionResponse::List(list) => list,
+            CompletionResponse::Items(items) => CompletionList {
+                is_incomplete: false,
+                items,
+            },
+        })
+    }
+}
+
+impl From<LspCompletionItem> for CompletionItem {
+    fn from(item: LspCompletionItem) -> Self {
+        let word = item.insert_word().to_string();
+        let abbr = if item.label != word {
+            Some(item.label.clone())
+        } else {
+            None
+        };
+
+        let info: Vec<&str> = item
+            .detail
+            .iter()
+            .map(|detail| detail.as_str())
+            .chain(item.documentation.iter().map(|doc| doc.value()))
+            .filter(|text| !text.is_empty())
+            .collect();
+
+        let kind = item.kind.map(|kind| kind.vim_kind().to_string());
+
+        // Keep the whole LSP item around, so Lua can apply `additionalTextEdits`
+        // and friends once the item is actually accepted.
+        let user_data = rmpv::ext::to_value(&item)
+            .ok()
+            .map(|lsp| Value::Map(vec![(Value::from("lsp"), lsp)]));
+
+        CompletionItem {
+            word,
+            abbr,
+            menu: Some(String::from("[L]")),
+            info: if info.is_empty() {
+                None
+            } else {
+                Some(info.join("\n\n"))
+            },
+            kind,
+            user_data,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::map;
+
+    fn range(start: u64, end: u64) -> Value {
+        let position = |character: u64| {
+            map(vec![
+                ("line", Value::from(0)),
+                ("character", Value::from(character)),
+            ])
+        };
+
+        map(vec![("start", position(start)), ("end", position(end))])
+    }
+
+    #[test]
+    fn test_decode_completion_list() {
+        let response = map(vec![
+            ("isIncomplete", Value::from(true)),
+            (
+                "items",
+                Value::Array(vec![map(vec![
+                    ("label", Value::from("println!")),
+                    ("kind", Value::from(3)),
+                    ("detail", Value::from("macro")),
+                    ("sortText", Value::from("0001")),
+                    ("commitCharacters", Value::Array(vec![Value::from("(")])),
+                ])]),
+            ),
+        ]);
+
+        let list = CompletionList::from_response(response).unwrap();
+        assert!(list.is_incomplete);
+        assert_eq!(1, list.items.len());
+
+        let item = &list.items[0];
+        assert_eq!("println!", item.label);
+        assert_eq!(Some(CompletionItemKind::Function), item.kind);
+        assert_eq!("0001", item.sort_text());
+        assert_eq!("println!", item.filter_text());
+        assert_eq!(Some(vec![String::from("(")]), item.commit_characters);
+    }
+
+    #[test]
+    fn test_decode_bare_items_and_nil() {
+        let response = Value::Array(vec![map(vec![("label", Value::from("hello"))])]);
+        let list = CompletionList::from_response(response).unwrap();
+        assert!(!list.is_incomplete);
+        assert_eq!("hello", list.items[0].label);
+
+        let list = CompletionList::from_response(Value::Nil).unwrap();
+        assert!(list.items.is_empty());
+    }
+
+    #[test]
+    fn test_text_edit_wins_over_insert_text() {
+        let response = Value::Array(vec![map(vec![
+            ("label", Value::from("vec")),
+            ("insertText", Value::from("vec_insert")),
+            (
+                "textEdit",
+                map(vec![
+                    ("range", range(0, 2)),
+                    ("newText", Value::from("vec_edit")),
+                ]),
+            ),
+            (
+                "additionalTextEdits",
+                Value::Array(vec![map(vec![
+                    ("range", range(0, 0)),
+                    ("newText", Value::from("use std::vec;\n")),
+                ])]),
+            ),
+            (
+                "documentation",
+                map(vec![
+                    ("kind", Value::from("markdown")),
+                    ("value", Value::from("A vector")),
+                ]),
+            ),
+        ])]);
+
+        let item = CompletionList::from_response(response)
+            .unwrap()
+            .items
+            .remove(0);
+        assert_eq!("vec_edit", item.insert_word());
+        assert_eq!(1, item.additional_text_edits.as_ref().unwrap().len());
+
+        let converted = CompletionItem::from(item);
+        assert_eq!("vec_edit", converted.word);
+        assert_eq!(Some(String::from("vec")), converted.abbr);
+        assert_eq!(Some(String::from("A vector")), converted.info);
+        assert!(converted.user_data.is_some());
+    }
+
+    #[test]
+    fn test_snippets_insert_their_label() {
+        let response = Value::Array(vec![map(vec![
+            ("label", Value::from("for")),
+            ("kind", Value::from(15)),
+            (
+                "insertText",
+                Value::from("for ${1:i} in ${2:iter} {\n\t$0\n}"),
+            ),
+            ("insertTextFormat", Value::from(2)),
+        ])]);
+
+        let item = CompletionList::from_response(response)
+            .unwrap()
+            .items
+            .remove(0);
+
+        let converted = CompletionItem::from(item);
+        assert_eq!("for", converted.word);
+        assert_eq!(None, converted.abbr);
+        assert_eq!(Some(String::from("s")), converted.kind);
+    }
+
+    #[test]
+    fn test_kind_from_name() {
+        assert_eq!(
+            Some(CompletionItemKind::Function),
+            CompletionItemKind::from_name("functions")
+        );
+        assert_eq!(
+            Some(CompletionItemKind::Class),
+            CompletionItemKind::from_name("classes")
+        );
+        assert_eq!(
+            Some(CompletionItemKind::Property),
+            CompletionItemKind::from_name("properties")
+        );
+        assert_eq!(
+            Some(CompletionItemKind::EnumMember),
+            CompletionItemKind::from_name("enum_member")
+        );
+        assert_eq!(None, CompletionItemKind::from_name("gadgets"));
+    }
+
+    #[test]
+    fn test_unknown_kinds_and_tags_are_left_out() {
+        let response = Value::Array(vec![
+            map(vec![
+                ("label", Value::from("hello")),
+                ("kind", Value::from(99)),
+                ("tags", Value::Array(vec![Value::from(1), Value::from(7)])),
+                ("insertTextFormat", Value::from(5)),
+            ]),
+            map(vec![
+                ("label", Value::from("world")),
+                ("kind", Value::from(3)),
+            ]),
+        ]);
+
+        let list = CompletionList::from_response(response).unwrap();
+        assert_eq!(2, list.items.len());
+
+        let hello = &list.items[0];
+        assert_eq!(None, hello.kind);
+        assert_eq!(Some(vec![CompletionItemTag::Deprecated]), hello.tags);
+        assert_eq!(None, hello.insert_text_format);
+        assert!(hello.is_deprecated());
+
+        assert_eq!(Some(CompletionItemKind::Function), list.items[1].kind);
+    }
+}
@@ -1,22 +1,42 @@
 // Erik recommends: https://tracing.rs/tracing/
 use async_trait::async_trait;
-use log::{error, info, LevelFilter};
+use config::{Config, SourceSetting};
+use document::{ByteEdit, DocumentStore, LineChange, Update};
+use futures::future::Abortable;
+use in_flight::InFlight;
+use log::{error, info, warn, LevelFilter};
 use nvim_rs::{compat::tokio::Compat, create::tokio as create, Handler, Neovim, Value};
+use rpc::{Args, DecodeError, Fields, Notification, Request, ServerInfo};
 use simplelog::WriteLogger;
-use sources::{BufferCompletionSource, CompletionSource, Completions, FileCompletionSource};
+use sources::{
+    BufferCompletionSource, Completions, FileCompletionSource, LspCompletionSource, SourceOptions,
+    SourceRegistry,
+};
 use std::{
     collections::HashMap,
     panic,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 use tokio::{runtime, sync::RwLock};
 
-mod collections;
-mod nvim;
+mod config;
+mod document;
+mod in_flight;
+mod lsp;
+mod rpc;
 mod sources;
+#[cfg(test)]
+mod testing;
 
-use nvim::iskeyword;
+use nvim::{
+    charset::{CharsetMatcher, CharsetOption},
+    iskeyword,
+};
+use rofl_nvim::{
+    collections::{Cursor, LineRange},
+    fuzzy, nvim, word_store,
+};
 
 #[derive(Debug)]
 pub struct CompletionContext {
@@ -28,82 +48,134 @@ pub struct CompletionContext {
 
     /// Current buffer
     bufnr: u64,
+
+    /// Cursor position in the current buffer, if Lua sent one
+    cursor: Option<Cursor>,
+
+    /// The line the cursor is on, if Lua sent it, or else from our copy of the buffer
+    line: Option<String>,
+
+    /// Buffers shown in the current tabpage
+    visible: Vec<u64>,
+
+    /// Lua's number for this request, which `cancel` refers to
+    request_id: Option<u64>,
+
+    /// 'filetype' of the current buffer, which picks its profile from the config.
+    /// Lua can send one, but it's usually the one the buffer was initialized with.
+    filetype: Option<String>,
     // Enabled sources
     // sources: HashMap<SourceType, CompletionSource>,
     // sources: Vec<CompletionSource>,
 }
 
-fn lookup_str_key(map: &Vec<(Value, Value)>, lookup_key: &str) -> String {
-    map.iter()
-        .find(|(key, _)| key.as_str().expect("string keys") == lookup_key)
-        .expect("TJ is dumb and twitch chat is smart")
-        .1
-        .as_str()
-        .expect("Did I send things gud")
-        .to_owned()
-}
+impl CompletionContext {
+    /// The context table `_get_context` builds in Lua.
+    fn decode(fields: &Fields) -> Result<Self, DecodeError> {
+        let word = fields.get("word")?;
+        let cwd: String = fields.get("cwd")?;
+        let bufnr = fields.get("bufnr")?;
+
+        // Sent straight from `nvim_win_get_cursor`, so it's `{ 1-indexed row, col }`
+        let line = fields.optional("line")?;
+        let cursor = fields
+            .optional::<(u64, u64)>("cursor")?
+            .map(|(row, col)| Cursor {
+                line: row.saturating_sub(1),
+                col,
+            });
+        let visible = fields.optional("visible")?.unwrap_or_default();
+        let request_id = fields.optional("request_id")?;
+        let filetype = fields
+            .optional::<String>("filetype")?
+            .filter(|filetype| !filetype.is_empty());
 
-fn lookup_u64_key(map: &Vec<(Value, Value)>, lookup_key: &str) -> u64 {
-    map.iter()
-        .find(|(key, _)| key.as_str().expect("string keys") == lookup_key)
-        .expect("TJ is dumb and twitch chat is smart // u64 way")
-        .1
-        .as_u64()
-        .expect("Did I send things gud")
-        .to_owned()
+        Ok(CompletionContext {
+            word,
+            cwd: Path::new(&cwd).into(),
+            bufnr,
+            cursor,
+            line,
+            visible,
+            request_id,
+            filetype,
+        })
+    }
 }
 
-impl From<Vec<(Value, Value)>> for CompletionContext {
-    fn from(map: Vec<(Value, Value)>) -> Self {
-        // I've got a vector of value value, which is key:value pairs
-        // I know the names of the values I want
-        // ....
-        //
-        // local ctx = {}
-        // for k, v in pairs(request) do ctx[k] = v end
-        // return ctx
-        //
-        //
-        // local word = nil
-        // for k, v in pairs(request) do if k == "word" then word = v end end
-
-        let word = lookup_str_key(&map, "word");
-        let cwd: PathBuf = Path::new(lookup_str_key(&map, "cwd").as_str()).into();
-        let bufnr = lookup_u64_key(&map, "bufnr");
-
-        CompletionContext { word, cwd, bufnr }
+#[cfg(test)]
+impl CompletionContext {
+    /// Completing `word` in buffer 1, with nothing else sent. Tests override what they need with `..`.
+    pub fn for_test(word: &str) -> Self {
+        CompletionContext {
+            word: String::from(word),
+            cwd: std::env::temp_dir(),
+            bufnr: 1,
+            cursor: None,
+            line: None,
+            visible: Vec::new(),
+            request_id: None,
+            filetype: None,
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct SourceContext {
-    /// Is file source enabled?
-    file: bool,
-
-    /// Is buffer source enabled?
-    buffer: bool,
+    /// Sources enabled for this request and their options, in the order they were sent
+    enabled: Vec<(String, Value)>,
 }
 
-impl From<Vec<(Value, Value)>> for SourceContext {
-    fn from(map: Vec<(Value, Value)>) -> Self {
-        let coerce_bool = |index| match index {
-            Value::Boolean(val) => val.clone(),
-            Value::Nil => false,
-            _ => false,
-        };
-
-        let mut file = false;
-        let mut buffer = false;
-        for (key, index) in map.iter() {
-            let key = key.as_str().expect("keys are strings");
-            if key == "file" {
-                file = coerce_bool(index.clone());
-            } else if key == "buffer" {
-                buffer = coerce_bool(index.clone());
+impl SourceContext {
+    /// The sources table. Lua can leave it out to get the ones from the config.
+    fn decode(fields: &Fields) -> Result<Self, DecodeError> {
+        // `file = true` and `file = { ... }` both enable a source,
+        // while `file = false` (or nil) leaves it out.
+        let mut enabled = Vec::new();
+        for (name, index) in fields.entries()? {
+            match SourceSetting::from_value(index) {
+                Some(SourceSetting::On(opts)) => enabled.push((name, opts)),
+                Some(SourceSetting::Off) => {}
+                None => {
+                    return Err(fields.error(
+                        &name,
+                        format!("expected a boolean or a table, got {}", index),
+                    ))
+                }
             }
         }
 
-        SourceContext { file, buffer }
+        Ok(SourceContext { enabled })
+    }
+
+    /// Make sure every requested source actually exists, and check the options
+    /// sent for each of them, so that a typo in Lua doesn't silently give you no
+    /// completions (or the wrong ones).
+    fn resolve(&self, registry: &SourceRegistry) -> Result<Vec<(String, SourceOptions)>, String> {
+        let unknown: Vec<&str> = self
+            .enabled
+            .iter()
+            .filter(|(name, _)| !registry.contains(name))
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        if !unknown.is_empty() {
+            return Err(format!(
+                "Unknown completion source(s): {}. Available sources: {}",
+                unknown.join(", "),
+                registry.names().join(", ")
+            ));
+        }
+
+        self.enabled
+            .iter()
+            .map(|(name, opts)| {
+                registry
+                    .options(name, opts)
+                    .map(|opts| (name.clone(), opts))
+                    .map_err(|err| err.to_string())
+            })
+            .collect()
     }
 }
 
@@ -111,205 +183,758 @@ impl From<Vec<(Value, Value)>> for SourceContext {
 struct NeovimHandler {
     iskeyword_map: Arc<RwLock<HashMap<u64, iskeyword::KeywordMatcher>>>,
 
-    file_completion: FileCompletionSource,
-    buffer_completion: Arc<Mutex<BufferCompletionSource>>,
-}
+    isfname: Arc<RwLock<CharsetMatcher>>,
 
-async fn buf_initialize(handler: &NeovimHandler, args: Vec<Value>) -> Result<Value, Value> {
-    let mut iskeyword_map = handler.iskeyword_map.write().await;
+    /// Global like 'isfname', for sources that go by these instead
+    isident: Arc<RwLock<CharsetMatcher>>,
+    isprint: Arc<RwLock<CharsetMatcher>>,
 
-    let bufnr = args[0].as_u64().expect("Yo dawg, send me those bufnrs");
-    let iskeyword_str = args[1].as_str().expect("iskeyword to be sent as a string");
+    /// Each attached buffer's 'filetype', if it has one
+    filetypes: Arc<RwLock<HashMap<u64, String>>>,
 
-    // info!("{:?}", nvim::iskeyword::transform(&iskeyword_str));
+    /// Our copy of each attached buffer's text
+    documents: Arc<DocumentStore>,
 
-    info!("old iskeyword {:?}", iskeyword_map);
-    iskeyword_map.insert(bufnr, iskeyword::transform(iskeyword_str));
-    info!("new iskeyword {:?}", iskeyword_map);
+    sources: Arc<SourceRegistry>,
 
-    Ok(Value::Nil)
+    /// The newest changedtick as-you-type completion was asked for, for each buffer,
+    /// so results for text that has changed since can be dropped
+    completing_at: Arc<RwLock<HashMap<u64, u64>>>,
+
+    /// Numbered completion requests that are still running
+    in_flight: Arc<InFlight>,
+
+    /// From `setup`, or the config file until then
+    config: Arc<RwLock<Config>>,
 }
 
-#[async_trait]
-impl Handler for NeovimHandler {
-    type Writer = Compat<tokio::io::Stdout>;
+impl NeovimHandler {
+    /// Let every source know which lines of a buffer changed.
+    async fn on_lines(&self, bufnr: u64, change: &LineChange) {
+        for (name, entry) in self.sources.iter() {
+            entry
+                .source
+                .on_lines(bufnr, change.start, change.old_end, &change.lines)
+                .await;
 
-    async fn handle_request(
+            info!("Completed buf attach lines for {}", name);
+        }
+    }
+
+    /// Apply update number `seq` from `nvim_buf_attach`, getting the whole buffer if it doesn't fit.
+    async fn update_document(
         &self,
-        name: String,
-        args: Vec<Value>,
-        _neovim: Neovim<Self::Writer>,
-    ) -> Result<Value, Value> {
-        info!("===========================================================");
-        info!("Request: {}, {:?}", name, args);
+        bufnr: u64,
+        changedtick: u64,
+        seq: u64,
+        update: Update,
+        neovim: &Neovim<Compat<tokio::io::Stdout>>,
+    ) {
+        let mut document = self.documents.lock(bufnr).await;
+        let applied = document.update(seq, update);
+        for change in &applied.changes {
+            self.on_lines(bufnr, change).await;
+        }
+        drop(document);
 
-        match name.as_ref() {
-            "find_start" => {
-                info!("======================= FIND START ==================================");
-                let current_bufnr = args[0].as_u64().expect("Bufnr");
-                let current_line = args[1].to_string();
-                let current_cursor = args[2].as_u64().expect("Should get a number");
+        if applied.needs_sync {
+            warn!(
+                "Buffer {} is out of sync at {} (update {})",
+                bufnr, changedtick, seq
+            );
+            self.sync_document(bufnr, neovim).await;
+        }
+    }
+
+    /// Forget everything we know about a buffer.
+    async fn detach(&self, bufnr: u64) {
+        self.documents.remove(bufnr).await;
+        self.iskeyword_map.write().await.remove(&bufnr);
+        self.filetypes.write().await.remove(&bufnr);
+        self.completing_at.write().await.remove(&bufnr);
+
+        for (_, entry) in self.sources.iter() {
+            entry.source.on_detach(bufnr).await;
+        }
+
+        info!("Detached buffer {}", bufnr);
+    }
+
+    /// 'iskeyword' changed, so every line has to be split into words again.
+    async fn set_iskeyword(
+        &self,
+        bufnr: u64,
+        iskeyword_str: &str,
+        neovim: &Neovim<Compat<tokio::io::Stdout>>,
+    ) {
+        self.iskeyword_map
+            .write()
+            .await
+            .insert(bufnr, iskeyword::transform(iskeyword_str));
+
+        let document = self.documents.lock(bufnr).await;
+        if document.is_synced() {
+            let change = LineChange {
+                start: 0,
+                old_end: u64::MAX,
+                lines: document.lines().to_vec(),
+            };
+            self.on_lines(bufnr, &change).await;
+        } else {
+            // Syncing hands every line to the sources anyway
+            drop(document);
+            self.sync_document(bufnr, neovim).await;
+        }
+    }
+
+    /// The sources a completion request gets, checked and with their options filled in:
+    /// the ones it sent, on top of the config, or just the config's if it sent none.
+    async fn requested_sources(
+        &self,
+        ctx: &CompletionContext,
+        args: &Args<'_>,
+        index: usize,
+    ) -> Result<Vec<(String, SourceOptions)>, DecodeError> {
+        let sent = match args.optional_fields(index, "sources")? {
+            Some(fields) => Some(SourceContext::decode(&fields)?.enabled),
+            None => None,
+        };
+
+        let enabled = self
+            .config
+            .read()
+            .await
+            .requested_sources(ctx.filetype.as_deref(), sent.as_deref());
+
+        SourceContext { enabled }
+            .resolve(&self.sources)
+            .map_err(|message| args.error("sources", message))
+    }
+
+    /// Check a new config and start using it.
+    async fn setup(&self, args: &Args<'_>) -> Result<Value, DecodeError> {
+        let config: Config = args.get(0, "config")?;
+        config
+            .validate(&self.sources)
+            .map_err(|err| args.error("config", format!("{:#}", err)))?;
 
+        if let Some(level) = config.log_level().ok().flatten() {
+            log::set_max_level(level);
+        }
+
+        info!("Setup: {:?}", config);
+        *self.config.write().await = config;
+
+        Ok(Value::Nil)
+    }
+
+    async fn set_filetype(&self, bufnr: u64, filetype: String) {
+        let mut filetypes = self.filetypes.write().await;
+        if filetype.is_empty() {
+            filetypes.remove(&bufnr);
+        } else {
+            info!("Buffer {} is {}", bufnr, filetype);
+            filetypes.insert(bufnr, filetype);
+        }
+    }
+
+    /// Decode the context of a completion request, and fill in the buffer's
+    /// filetype if it didn't come with one.
+    async fn context(&self, fields: &Fields<'_>) -> Result<CompletionContext, DecodeError> {
+        let mut ctx = CompletionContext::decode(fields)?;
+        if ctx.filetype.is_none() {
+            ctx.filetype = self.filetypes.read().await.get(&ctx.bufnr).cloned();
+        }
+
+        Ok(ctx)
+    }
+
+    /// Where the word being completed starts. Each source decides what it completes,
+    /// e.g. files want the whole path. `None` if we don't know the buffer yet.
+    async fn find_start(
+        &self,
+        bufnr: u64,
+        line: &str,
+        cursor: u64,
+        source_name: Option<&str>,
+    ) -> Option<LineRange> {
+        let charset = source_name
+            .and_then(|source_name| self.sources.get(source_name))
+            .map_or(CharsetOption::IsKeyword, |entry| entry.source.charset());
+
+        match charset {
+            CharsetOption::IsKeyword => {
                 let iskeyword_map = self.iskeyword_map.read().await;
+                Some(iskeyword_map.get(&bufnr)?.find(line, cursor))
+            }
+            CharsetOption::IsFname => Some(self.isfname.read().await.find(line, cursor)),
+            CharsetOption::IsIdent => Some(self.isident.read().await.find(line, cursor)),
+            CharsetOption::IsPrint => Some(self.isprint.read().await.find(line, cursor)),
+        }
+    }
+
+    /// Note that as-you-type completion was asked for at `changedtick`. Notifications
+    /// can be handled out of order, so the keystroke before this one may still be
+    /// coming; `false` means a newer one has already been seen and this one is stale.
+    async fn start_completing(&self, bufnr: u64, changedtick: u64) -> bool {
+        let mut completing_at = self.completing_at.write().await;
+        let latest = completing_at.entry(bufnr).or_insert(changedtick);
+        if *latest > changedtick {
+            return false;
+        }
+        *latest = changedtick;
+        true
+    }
+
+    /// What's been typed of the word at the cursor so far, not the rest of it after
+    /// the cursor, along with where the whole word is. `None` if there's nothing to complete.
+    async fn typed_word(
+        &self,
+        bufnr: u64,
+        line: &str,
+        cursor: u64,
+        source_name: Option<&str>,
+    ) -> Option<(LineRange, String)> {
+        let line_range = self.find_start(bufnr, line, cursor, source_name).await?;
+        let typed = &line[line_range.start..line_range.cursor];
+        if typed.is_empty() {
+            return None;
+        }
+        let typed = typed.to_string();
+        Some((line_range, typed))
+    }
 
-                let iskeyword_option = iskeyword_map.get(&current_bufnr);
-                if iskeyword_option.is_none() {
-                    return Ok(Value::from(-1));
+    /// Ask the sources for completions, and refresh the ones that want it in the background.
+    ///
+    /// `None` if the request was cancelled, or superseded by a newer one, before it was done.
+    async fn gather(
+        &self,
+        ctx: CompletionContext,
+        requested: &[(String, SourceOptions)],
+        neovim: &Neovim<Compat<tokio::io::Stdout>>,
+    ) -> Option<Completions> {
+        let bufnr = ctx.bufnr;
+        let request_id = ctx.request_id;
+        let policy = self
+            .config
+            .read()
+            .await
+            .merge_policy(ctx.filetype.as_deref());
+        let complete = self.sources.complete(requested, Arc::new(ctx), &policy);
+
+        let gathered = match request_id {
+            Some(request_id) => {
+                let registration = self.in_flight.start(request_id)?;
+                // Aborting drops the sources' work along with it
+                let gathered = Abortable::new(complete, registration).await;
+                if !self.in_flight.finish(request_id) {
+                    info!("Request {} was cancelled", request_id);
+                    return None;
                 }
+                gathered.ok()?
+            }
+            None => complete.await,
+        };
 
-                let keyword_matcher = iskeyword_option.expect("Has to be it now");
-                let line_range = keyword_matcher.find(&current_line, current_cursor);
-                let current_slice = &current_line.to_string()[line_range.start..line_range.finish];
+        if !gathered.timed_out.is_empty() {
+            warn!("Sources timed out: {:?}", gathered.timed_out);
+        }
+        if !gathered.failed.is_empty() {
+            warn!("Sources failed: {:?}", gathered.failed);
+        }
 
-                info!("find_start: {}, {:?}", current_cursor, current_slice);
+        // Don't hold up these results, the refreshed ones will be there next time
+        for source_name in gathered.incomplete {
+            let neovim = neovim.clone();
+            tokio::spawn(async move {
+                let refresh = neovim
+                    .exec_lua(
+                        "require('rofl').refresh(...)",
+                        vec![Value::from(source_name.as_str()), Value::from(bufnr)],
+                    )
+                    .await;
 
-                // minus 1 because indexing
-                Ok(Value::from(line_range.start - 1))
+                if let Err(err) = refresh {
+                    error!("Failed to refresh {}: {}", source_name, err);
+                }
+            });
+        }
+
+        let completions = gathered.completions;
+        info!("{:?}", completions);
+
+        Some(completions)
+    }
+
+    /// As-you-type completion: work out the completions for what was just typed,
+    /// and show them with `complete()` if nothing has moved on since.
+    ///
+    /// `context, sources, source`, the same as `complete_sync` plus the source that
+    /// decides where the word starts, like `find_start`. The context has to have the
+    /// `changedtick`, `cursor` and `line` it was taken at.
+    async fn complete_async(
+        &self,
+        args: &Args<'_>,
+        neovim: Neovim<Compat<tokio::io::Stdout>>,
+    ) -> Result<(), DecodeError> {
+        let fields = args.fields(0, "context")?;
+        let mut ctx = self.context(&fields).await?;
+        let changedtick: u64 = fields.get("changedtick")?;
+        let (cursor, line) = match (ctx.cursor, ctx.line.clone()) {
+            (Some(cursor), Some(line)) => (cursor, line),
+            _ => return Err(fields.error("cursor", "needs to be sent along with `line`")),
+        };
+
+        let bufnr = ctx.bufnr;
+        if !self.start_completing(bufnr, changedtick).await {
+            return Ok(());
+        }
+
+        let requested = self.requested_sources(&ctx, args, 1).await?;
+        let source_name: Option<String> = args.optional(2, "source")?;
+
+        let (line_range, typed) = match self
+            .typed_word(bufnr, &line, cursor.col, source_name.as_deref())
+            .await
+        {
+            Some(typed) => typed,
+            None => return Ok(()),
+        };
+        ctx.word = typed;
+
+        let completions = match self.gather(ctx, &requested, &neovim).await {
+            Some(completions) => completions,
+            None => return Ok(()),
+        };
+
+        // Another keystroke came in while we were at it, its results will be along shortly
+        let latest = self.completing_at.read().await.get(&bufnr).copied();
+        if latest != Some(changedtick) {
+            info!("Dropping completions for {:?}, they're stale", line);
+            return Ok(());
+        }
+
+        let items = Value::Array(completions.items.into_iter().map(Value::from).collect());
+        let shown = neovim
+            .exec_lua(
+                "return require('rofl')._show_completions(...)",
+                vec![
+                    Value::from(bufnr),
+                    Value::from(changedtick),
+                    Value::Array(vec![Value::from(cursor.line + 1), Value::from(cursor.col)]),
+                    Value::from(line_range.start),
+                    items,
+                ],
+            )
+            .await;
+
+        match shown {
+            Ok(Value::Boolean(false)) => info!("Neovim moved on, didn't show completions"),
+            Ok(_) => {}
+            Err(err) => error!("Failed to show completions: {}", err),
+        }
+
+        Ok(())
+    }
+
+    /// Get the whole buffer from Neovim, and start over from there.
+    async fn sync_document(&self, bufnr: u64, neovim: &Neovim<Compat<tokio::io::Stdout>>) {
+        for _ in 0..SYNC_ATTEMPTS {
+            let buffer = neovim
+                .exec_lua(
+                    "local bufnr = ...
+                    return {
+                      vim.api.nvim_buf_get_changedtick(bufnr),
+                      require('rofl')._get_seq(bufnr),
+                      vim.api.nvim_buf_get_lines(bufnr, 0, -1, false),
+                    }",
+                    vec![Value::from(bufnr)],
+                )
+                .await;
+
+            let (changedtick, seq, lines) = match buffer.ok().as_ref().and_then(parse_buffer) {
+                Some(buffer) => buffer,
+                None => {
+                    error!("Failed to get the text of buffer {}", bufnr);
+                    self.documents.sync_failed(bufnr).await;
+                    return;
+                }
+            };
+
+            let mut document = self.documents.lock(bufnr).await;
+            let applied = document.sync(seq, lines);
+            for change in &applied.changes {
+                self.on_lines(bufnr, change).await;
             }
-            "complete" => {
-                info!("======================= COMPLETE ==================================");
-                Ok(Value::Array(vec![Value::from("hello")]))
+            drop(document);
+
+            // Something that came in while we were syncing didn't fit either
+            if applied.needs_sync {
+                warn!("Buffer {} is still out of sync", bufnr);
+                continue;
             }
-            "complete_sync" => {
-                // TODO: Read about:
-                // - try_unpack
-                // - move vs clone
-                // - Results
-                // - Option vs Result
-                //
-                // - Other:
-                //  - iterators
-                //  - iter() vs into_iter() vs iter_mut()
-                //  - collect()
-                //
-                //  - https://upsuper.github.io/rust-cheatsheet/?dark
-                //
-                //  - interior mutability
-                //  - RefCel
-                //
-                // let map_context = args[0].as_map().expect("map_context").clone();
-                // let map_context: Vec<(Value, Value)> = args[0].try_unpack().expect("map_context");
-                // let map_context = args[0].as_map().iter.map(|(key, value)| ...);
-                let map_context_value = args[0].as_map().expect("map_context").clone();
-                let map_context = CompletionContext::from(map_context_value);
-                info!("context: {:?}", map_context);
 
-                let source_context_value = args[1].as_map().unwrap_or(&Vec::new()).clone();
-                let source_context = SourceContext::from(source_context_value);
+            info!("Synced buffer {} at {}", bufnr, changedtick);
+            return;
+        }
+
+        error!("Giving up on syncing buffer {}", bufnr);
+        self.documents.sync_failed(bufnr).await;
+    }
+}
+
+/// How many times to get the whole buffer before we stop trying.
+const SYNC_ATTEMPTS: usize = 3;
+
+/// `{ changedtick, seq, lines }`, from `sync_document`
+fn parse_buffer(buffer: &Value) -> Option<(u64, u64, Vec<String>)> {
+    match buffer.as_array()?.as_slice() {
+        [changedtick, seq, lines] => {
+            Some((changedtick.as_u64()?, seq.as_u64()?, parse_lines(lines)?))
+        }
+        _ => None,
+    }
+}
+
+/// Every update from `nvim_buf_attach` starts with `bufnr, changedtick, seq`,
+/// where `seq` is Lua's number for the callback.
+fn decode_update_header(args: &Args) -> Result<(u64, u64, u64), DecodeError> {
+    Ok((
+        args.get(0, "bufnr")?,
+        args.get(1, "changedtick")?,
+        args.get(2, "seq")?,
+    ))
+}
+
+fn parse_lines(lines: &Value) -> Option<Vec<String>> {
+    lines
+        .as_array()?
+        .iter()
+        .map(|line| line.as_str().map(String::from))
+        .collect()
+}
+
+/// `buf_attach_bytes` is sent straight from `on_bytes`, minus the byte offsets we don't use:
+/// `bufnr, changedtick, seq, start_row, start_col, old_end_row, old_end_col, new_end_row, new_end_col, text`
+fn decode_byte_edit(args: &Args) -> Result<ByteEdit, DecodeError> {
+    Ok(ByteEdit {
+        start_row: args.get(3, "start_row")?,
+        start_col: args.get(4, "start_col")?,
+        old_end_row: args.get(5, "old_end_row")?,
+        old_end_col: args.get(6, "old_end_col")?,
+        new_end_row: args.get(7, "new_end_row")?,
+        new_end_col: args.get(8, "new_end_col")?,
+        text: args.get(9, "text")?,
+    })
+}
 
-                let mut completions: Completions = Completions { items: Vec::new() };
+async fn buf_initialize(
+    handler: &NeovimHandler,
+    args: &Args<'_>,
+    neovim: Neovim<Compat<tokio::io::Stdout>>,
+) -> Result<Value, DecodeError> {
+    let bufnr = args.get(0, "bufnr")?;
+    let iskeyword_str: String = args.get(1, "iskeyword")?;
+    let isfname_str: Option<String> = args.optional(2, "isfname")?;
+    let filetype: Option<String> = args.optional(3, "filetype")?;
+    let isident_str: Option<String> = args.optional(4, "isident")?;
+    let isprint_str: Option<String> = args.optional(5, "isprint")?;
+
+    let mut iskeyword_map = handler.iskeyword_map.write().await;
 
-                // TODO: Decide on mutability
-                if source_context.file {
-                    let completions_file = self.file_completion.complete(&map_context);
-                    if let Ok(c) = completions_file {
-                        info!("Adding file completions");
-                        completions.items.extend(c.items)
+    info!("old iskeyword {:?}", iskeyword_map);
+    iskeyword_map.insert(bufnr, iskeyword::transform(&iskeyword_str));
+    info!("new iskeyword {:?}", iskeyword_map);
+    drop(iskeyword_map);
+
+    // These are global, but this is as good a time as any to hear about them
+    for (charset, value, matcher) in [
+        (CharsetOption::IsFname, isfname_str, &handler.isfname),
+        (CharsetOption::IsIdent, isident_str, &handler.isident),
+        (CharsetOption::IsPrint, isprint_str, &handler.isprint),
+    ] {
+        if let Some(value) = value {
+            *matcher.write().await = CharsetMatcher::parse_or_default(charset, &value);
+        }
+    }
+
+    if let Some(filetype) = filetype {
+        handler.set_filetype(bufnr, filetype).await;
+    }
+
+    // Neovim is waiting on us if this was a request, so get the text afterwards
+    let handler = handler.clone();
+    tokio::spawn(async move { handler.sync_document(bufnr, &neovim).await });
+
+    Ok(Value::Nil)
+}
+
+impl NeovimHandler {
+    async fn request(
+        &self,
+        method: Request,
+        args: &Args<'_>,
+        neovim: Neovim<Compat<tokio::io::Stdout>>,
+    ) -> Result<Value, Value> {
+        match method {
+            Request::Initialize => {
+                // Lua checks the protocol, but a mismatch is worth having in our log too
+                let client_protocol: Option<u64> = args.optional(0, "protocol")?;
+                if let Some(client_protocol) = client_protocol {
+                    if client_protocol != rpc::PROTOCOL_VERSION {
+                        warn!(
+                            "Client speaks protocol {}, but we speak {}",
+                            client_protocol,
+                            rpc::PROTOCOL_VERSION
+                        );
                     }
                 }
 
-                if source_context.buffer {
-                    let completions_buffer = self
-                        .buffer_completion
-                        .lock()
-                        .expect("gets the lock")
-                        .complete(&map_context);
+                let sources = self.sources.names().into_iter().map(String::from).collect();
+                Ok(Value::from(ServerInfo::new(sources)))
+            }
+            Request::FindStart => {
+                info!("======================= FIND START ==================================");
+                let current_bufnr: u64 = args.get(0, "bufnr")?;
+                let current_line: String = args.get(1, "line")?;
+                let current_cursor: u64 = args.get(2, "cursor")?;
+                let source_name: Option<String> = args.optional(3, "source")?;
+
+                let line_range = match self
+                    .find_start(
+                        current_bufnr,
+                        &current_line,
+                        current_cursor,
+                        source_name.as_deref(),
+                    )
+                    .await
+                {
+                    Some(line_range) => line_range,
+                    None => return Ok(Value::from(-1)),
+                };
+                let current_slice = &current_line[line_range.start..line_range.finish];
+
+                info!("find_start: {}, {:?}", current_cursor, current_slice);
+
+                // Already the byte column `complete()` wants
+                Ok(Value::from(line_range.start))
+            }
+            Request::CompleteSync => {
+                let map_context = self.context(&args.fields(0, "context")?).await?;
+                info!("context: {:?}", map_context);
+
+                let requested = self.requested_sources(&map_context, args, 1).await?;
 
-                    if let Ok(c) = completions_buffer {
-                        info!("Adding buffer completions");
-                        completions.items.extend(c.items)
+                let request_id = map_context.request_id;
+                let completions = match self.gather(map_context, &requested, &neovim).await {
+                    Some(completions) => completions,
+                    // Stale results are dropped, not an error for 'completefunc' to show
+                    None => {
+                        info!(
+                            "complete_sync: request {} was cancelled",
+                            request_id.unwrap_or_default()
+                        );
+                        return Ok(Value::Array(Vec::new()));
                     }
-                }
+                };
 
-                let array = Value::Array(
-                    completions
-                        .items
-                        .iter()
-                        .map(|x| Value::from(x.word.clone()))
-                        .collect(),
-                );
+                let array = Value::Array(completions.items.into_iter().map(Value::from).collect());
 
-                info!("{:?}", completions);
                 info!("Array: {:?}", &array);
 
                 Ok(array)
             }
-            "buf_initialize" => {
-                return buf_initialize(self, args).await;
-            }
-            _ => Ok(Value::from(3)),
+            Request::Setup => Ok(self.setup(args).await?),
+            Request::BufInitialize => Ok(buf_initialize(self, args, neovim).await?),
         }
     }
 
-    async fn handle_notify(&self, name: String, args: Vec<Value>, _neovim: Neovim<Self::Writer>) {
-        match name.as_ref() {
-            "complete" => {}
-            "v_char" => {}
-            "insert_leave" => {}
-            "buf_initialize" => {
-                let _ = buf_initialize(self, args).await;
+    async fn notify(
+        &self,
+        method: Notification,
+        args: &Args<'_>,
+        neovim: Neovim<Compat<tokio::io::Stdout>>,
+    ) -> Result<(), DecodeError> {
+        match method {
+            Notification::Complete => self.complete_async(args, neovim).await?,
+            Notification::Cancel => self.in_flight.cancel(args.get(0, "request_id")?),
+            Notification::BufInitialize => {
+                buf_initialize(self, args, neovim).await?;
+            }
+            Notification::BufDetach | Notification::BufWipe => {
+                self.detach(args.get(0, "bufnr")?).await
             }
-            "buf_attach_lines" => {
+            // `:edit` doesn't send any edits, the whole buffer just changes
+            Notification::BufReload => self.sync_document(args.get(0, "bufnr")?, &neovim).await,
+            Notification::BufIskeyword => {
+                let bufnr = args.get(0, "bufnr")?;
+                let iskeyword_str: String = args.get(1, "iskeyword")?;
+                self.set_iskeyword(bufnr, &iskeyword_str, &neovim).await
+            }
+            Notification::BufFiletype => {
+                let bufnr = args.get(0, "bufnr")?;
+                let filetype = args.get(1, "filetype")?;
+                self.set_filetype(bufnr, filetype).await
+            }
+            Notification::BufAttachLines => {
                 info!("Calling buf attach lines");
 
-                // Call all the `on_attach` methods for existing sources.
-                let bufnr = args[0].as_u64().expect("bufnr");
-                let start_line = args[1].as_u64().expect("start_line");
-                let final_line = args[2].as_u64().expect("final_line");
-                let resulting_lines: Vec<String> = args[3]
-                    .as_array()
-                    .expect("resulting_lines")
-                    .iter()
-                    .map(|val| val.as_str().expect("Sent strings").to_string())
-                    .collect();
-
-                self.buffer_completion.lock().expect("locked").on_lines(
-                    bufnr,
-                    start_line,
-                    final_line,
-                    &resulting_lines,
-                );
-
-                info!(
-                    "Completed buf attach lines {:?}",
-                    self.buffer_completion.lock().expect("locked")
-                );
+                let (bufnr, changedtick, seq) = decode_update_header(args)?;
+                let update = Update::Lines {
+                    start: args.get(3, "start_line")?,
+                    old_end: args.get(4, "final_line")?,
+                    lines: args.get(5, "resulting_lines")?,
+                };
+                self.update_document(bufnr, changedtick, seq, update, &neovim)
+                    .await;
+            }
+            Notification::BufAttachBytes => {
+                let (bufnr, changedtick, seq) = decode_update_header(args)?;
+                let edit = decode_byte_edit(args)?;
+
+                // Only the lines the edit touched get handed to the sources
+                self.update_document(bufnr, changedtick, seq, Update::Bytes(edit), &neovim)
+                    .await;
+            }
+            Notification::BufChangedtick => {
+                let (bufnr, changedtick, seq) = decode_update_header(args)?;
+                self.update_document(bufnr, changedtick, seq, Update::Tick, &neovim)
+                    .await
+            }
+            Notification::SourceNotify => {
+                // Lets sources have their own notifications, e.g. LSP responses
+                let source_name: String = args.get(0, "source")?;
+                let method: String = args.get(1, "method")?;
+
+                match self.sources.get(&source_name) {
+                    Some(entry) => {
+                        if let Err(err) = entry.source.on_notify(&method, args.rest(2)).await {
+                            error!("{} failed to handle {}: {}", source_name, method, err);
+                        }
+                    }
+                    None => {
+                        return Err(args.error("source", format!("unknown source: {}", source_name)))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler for NeovimHandler {
+    type Writer = Compat<tokio::io::Stdout>;
+
+    async fn handle_request(
+        &self,
+        name: String,
+        args: Vec<Value>,
+        neovim: Neovim<Self::Writer>,
+    ) -> Result<Value, Value> {
+        info!("===========================================================");
+        info!("Request: {}, {:?}", name, args);
+
+        let result = match Request::from_name(&name) {
+            Some(method) => self.request(method, &Args::new(&name, &args), neovim).await,
+            None => Err(Value::from(format!("Unknown method: {}", name))),
+        };
+        if let Err(err) = &result {
+            error!("{}", err);
+        }
+
+        result
+    }
+
+    async fn handle_notify(&self, name: String, args: Vec<Value>, neovim: Neovim<Self::Writer>) {
+        let method = match Notification::from_name(&name) {
+            Some(method) => method,
+            None => {
+                warn!("Unknown notification: {}", name);
+                return;
             }
-            _ => (),
+        };
+
+        // Nobody is waiting on an answer, so the log is all we've got
+        if let Err(err) = self.notify(method, &Args::new(&name, &args), neovim).await {
+            error!("{}", err);
         }
     }
 }
 
 async fn run() {
+    // New sources only need to be registered here to be available from Lua
+    let iskeyword_map = Arc::new(RwLock::new(HashMap::new()));
+    let isfname = Arc::new(RwLock::new(CharsetMatcher::default_for(
+        CharsetOption::IsFname,
+    )));
+
+    let mut sources = SourceRegistry::default();
+    sources.register(FileCompletionSource::new(isfname.clone()));
+    sources.register(BufferCompletionSource::new(iskeyword_map.clone()));
+    sources.register(LspCompletionSource::default());
+
+    // Read before the logger starts, since it says where the log goes. A config
+    // that doesn't check out is ignored as a whole, rather than half used.
+    let config_path = dirs_next::config_dir().map(|dir| dir.join(config::CONFIG_FILE));
+    let loaded = match &config_path {
+        Some(config_path) => Config::load(config_path).and_then(|config| {
+            let config = config.unwrap_or_default();
+            config.validate(&sources)?;
+            Ok(config)
+        }),
+        None => Ok(Config::default()),
+    };
+    let config = loaded.as_ref().ok().cloned().unwrap_or_default();
+
+    let log_path = config.log_path.clone().unwrap_or_else(|| {
+        dirs_next::cache_dir()
+            .expect("Failed to get cache dir")
+            .join("nvim")
+            .join("rofl.log")
+    });
+    let log_level = config
+        .log_level()
+        .ok()
+        .flatten()
+        .unwrap_or(LevelFilter::Debug);
+
     let (nvim, io_handler) = create::new_parent(NeovimHandler {
-        iskeyword_map: Arc::new(RwLock::new(HashMap::new())),
-
-        // TODO: We should actually make it so that we have some hashmap of
-        // completion source names -> completion sources.
-        //
-        // This way we can just register and add them as we go
-        //
-        // Then you can only request from sources, etc.
-        file_completion: FileCompletionSource {},
-        buffer_completion: Arc::new(Mutex::new(BufferCompletionSource {
-            word_store: HashMap::new(),
-        })),
+        iskeyword_map,
+        isfname,
+        isident: Arc::new(RwLock::new(CharsetMatcher::default_for(
+            CharsetOption::IsIdent,
+        ))),
+        isprint: Arc::new(RwLock::new(CharsetMatcher::default_for(
+            CharsetOption::IsPrint,
+        ))),
+        filetypes: Arc::new(RwLock::new(HashMap::new())),
+        documents: Arc::new(DocumentStore::default()),
+        sources: Arc::new(sources),
+        completing_at: Arc::new(RwLock::new(HashMap::new())),
+        in_flight: Arc::new(InFlight::default()),
+        config: Arc::new(RwLock::new(config)),
     })
     .await;
 
-    let cache_path = dirs_next::cache_dir()
-        .expect("Failed to get cache dir")
-        .join("nvim");
-
     // should be okay to be synchronous
-    std::fs::create_dir_all(&cache_path).expect("Failed to create cache dir");
+    if let Some(log_dir) = log_path.parent() {
+        std::fs::create_dir_all(log_dir).expect("Failed to create log dir");
+    }
 
+    // Everything goes through the logger, so `setup` can turn the level up later
     WriteLogger::init(
-        LevelFilter::Debug,
+        LevelFilter::Trace,
         simplelog::Config::default(),
-        std::fs::File::create(cache_path.join("rofl.log")).expect("Failed to create log file"),
+        std::fs::File::create(&log_path).expect("Failed to create log file"),
     )
     .expect("Failed to start logger");
+    log::set_max_level(log_level);
+
+    if let (Err(err), Some(config_path)) = (&loaded, &config_path) {
+        error!("Ignoring {}: {:#}", config_path.display(), err);
+    }
 
     // we do not want to crash when panicking, instead log it
     panic::set_hook(Box::new(move |panic| {
@@ -343,3 +968,175 @@ fn main() {
         .expect("Failed to build runtime");
     runtime.block_on(run())
 }
+
+#[cfg(test)]
+impl NeovimHandler {
+    /// A handler with the usual sources and the default config, where buffer 1 is
+    /// attached with the default 'iskeyword'.
+    fn for_test() -> Self {
+        let mut iskeyword = HashMap::new();
+        iskeyword.insert(1, CharsetMatcher::default());
+        let iskeyword_map = Arc::new(RwLock::new(iskeyword));
+        let isfname = Arc::new(RwLock::new(CharsetMatcher::default_for(
+            CharsetOption::IsFname,
+        )));
+
+        let mut sources = SourceRegistry::default();
+        sources.register(FileCompletionSource::new(isfname.clone()));
+        sources.register(BufferCompletionSource::new(iskeyword_map.clone()));
+
+        NeovimHandler {
+            iskeyword_map,
+            isfname,
+            isident: Arc::new(RwLock::new(CharsetMatcher::default_for(
+                CharsetOption::IsIdent,
+            ))),
+            isprint: Arc::new(RwLock::new(CharsetMatcher::default_for(
+                CharsetOption::IsPrint,
+            ))),
+            filetypes: Arc::new(RwLock::new(HashMap::new())),
+            documents: Arc::new(DocumentStore::default()),
+            sources: Arc::new(sources),
+            completing_at: Arc::new(RwLock::new(HashMap::new())),
+            in_flight: Arc::new(InFlight::default()),
+            config: Arc::new(RwLock::new(Config::default())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::map;
+
+    fn context_args(bufnr: u64, filetype: Option<&str>) -> Vec<Value> {
+        let mut context = vec![
+            ("word", Value::from("hel")),
+            ("cwd", Value::from("/tmp")),
+            ("bufnr", Value::from(bufnr)),
+        ];
+        if let Some(filetype) = filetype {
+            context.push(("filetype", Value::from(filetype)));
+        }
+        vec![map(context)]
+    }
+
+    async fn context(handler: &NeovimHandler, values: &[Value]) -> CompletionContext {
+        let args = Args::new("complete_sync", values);
+        handler
+            .context(&args.fields(0, "context").unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stored_filetype() {
+        let handler = NeovimHandler::for_test();
+        handler.set_filetype(1, String::from("rust")).await;
+
+        let ctx = context(&handler, &context_args(1, None)).await;
+        assert_eq!(Some("rust"), ctx.filetype.as_deref());
+
+        // What the request says wins
+        let ctx = context(&handler, &context_args(1, Some("lua"))).await;
+        assert_eq!(Some("lua"), ctx.filetype.as_deref());
+
+        let ctx = context(&handler, &context_args(2, None)).await;
+        assert_eq!(None, ctx.filetype);
+
+        // `set filetype=` leaves the buffer without one
+        handler.set_filetype(1, String::new()).await;
+        let ctx = context(&handler, &context_args(1, None)).await;
+        assert_eq!(None, ctx.filetype);
+        assert!(handler.filetypes.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_filetype_profile_picks_the_sources() {
+        let handler = NeovimHandler::for_test();
+        *handler.config.write().await = toml::from_str(
+            r#"
+            [filetypes.gitcommit.sources]
+            buffer = { min_length = 3 }
+            file = false
+            "#,
+        )
+        .unwrap();
+        handler.set_filetype(1, String::from("gitcommit")).await;
+
+        let values = context_args(1, None);
+        let ctx = context(&handler, &values).await;
+        let args = Args::new("complete_sync", &values);
+        let requested = handler.requested_sources(&ctx, &args, 1).await.unwrap();
+        assert_eq!(1, requested.len());
+        assert_eq!("buffer", requested[0].0);
+        assert_eq!(Some(3), requested[0].1.count("min_length"));
+
+        // Other buffers get the usual ones
+        let values = context_args(2, None);
+        let ctx = context(&handler, &values).await;
+        let args = Args::new("complete_sync", &values);
+        let requested = handler.requested_sources(&ctx, &args, 1).await.unwrap();
+        let names: Vec<_> = requested.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(vec!["buffer", "file"], names);
+    }
+
+    #[tokio::test]
+    async fn test_older_keystrokes_are_dropped() {
+        let handler = NeovimHandler::for_test();
+
+        assert!(handler.start_completing(1, 5).await);
+        assert!(handler.start_completing(1, 7).await);
+        // Handled after the one at 7, even though it was sent before
+        assert!(!handler.start_completing(1, 6).await);
+        assert!(handler.start_completing(1, 7).await);
+
+        // Other buffers have their own changedticks
+        assert!(handler.start_completing(2, 1).await);
+        assert_eq!(Some(&7), handler.completing_at.read().await.get(&1));
+    }
+
+    async fn typed(
+        handler: &NeovimHandler,
+        bufnr: u64,
+        line: &str,
+        cursor: u64,
+        source_name: Option<&str>,
+    ) -> Option<String> {
+        let (_, typed) = handler.typed_word(bufnr, line, cursor, source_name).await?;
+        Some(typed)
+    }
+
+    #[tokio::test]
+    async fn test_typed_word_stops_at_the_cursor() {
+        let handler = NeovimHandler::for_test();
+
+        assert_eq!(
+            Some(String::from("hel")),
+            typed(&handler, 1, "say hello", 7, None).await
+        );
+        assert_eq!(
+            Some(String::from("hello")),
+            typed(&handler, 1, "say hello", 9, None).await
+        );
+        assert_eq!(None, typed(&handler, 1, "say hello", 4, None).await);
+        assert_eq!(
+            Some(String::from("./d")),
+            typed(&handler, 1, "edit ./dé/x", 9, Some("file")).await
+        );
+
+        // Buffers we haven't been told about have no 'iskeyword' yet
+        assert_eq!(None, typed(&handler, 2, "say hello", 7, None).await);
+    }
+
+    #[tokio::test]
+    async fn test_typed_word_in_the_middle_of_a_character() {
+        let handler = NeovimHandler::for_test();
+
+        // Column 4 is the second byte of the é
+        assert_eq!(
+            Some(String::from("caf")),
+            typed(&handler, 1, "café", 4, None).await
+        );
+    }
+}
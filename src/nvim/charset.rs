@@ -0,0 +1,378 @@
+// Vim has a handful of options that are sets of characters, all written the same
+// way (see `:help isfname`): 'iskeyword', 'isfname', 'isident' and 'isprint'.
+// They only differ in their defaults and in what happens to characters past 255,
+// which the option itself can't mention.
+
+use std::fmt;
+
+use log::error;
+
+use crate::collections::LineRange;
+
+/// The option only covers characters below this.
+const TABLE_SIZE: u32 = 256;
+
+/// Which option a `CharsetMatcher` was built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CharsetOption {
+    IsKeyword,
+    IsFname,
+    IsIdent,
+    IsPrint,
+}
+
+impl CharsetOption {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CharsetOption::IsKeyword => "iskeyword",
+            CharsetOption::IsFname => "isfname",
+            CharsetOption::IsIdent => "isident",
+            CharsetOption::IsPrint => "isprint",
+        }
+    }
+
+    /// What `:set {option}?` says in a fresh Neovim on Unix.
+    pub fn default_value(&self) -> &'static str {
+        match self {
+            CharsetOption::IsKeyword => super::iskeyword::DEFAULT_ISKEYWORD,
+            CharsetOption::IsFname => "@,48-57,/,.,-,_,+,,,#,$,%,~,=",
+            CharsetOption::IsIdent => "@,48-57,_,192-255",
+            CharsetOption::IsPrint => "@,161-255",
+        }
+    }
+
+    /// Whether a character past `TABLE_SIZE` is in the set, which is up to Vim and not the option.
+    fn includes_wide(&self, c: char) -> bool {
+        match self {
+            CharsetOption::IsKeyword => is_unicode_word_char(c),
+            CharsetOption::IsFname | CharsetOption::IsPrint => true,
+            CharsetOption::IsIdent => false,
+        }
+    }
+}
+
+/// Roughly Vim's `utf_class()`: is a character past `TABLE_SIZE` part of words?
+/// CJK and emoji are, the Unicode spaces and punctuation blocks aren't.
+fn is_unicode_word_char(c: char) -> bool {
+    let punctuation = [
+        ('\u{2000}', '\u{206f}'), // General Punctuation, including spaces
+        ('\u{2e00}', '\u{2e7f}'), // Supplemental Punctuation
+        ('\u{3000}', '\u{303f}'), // CJK Symbols and Punctuation
+        ('\u{fe30}', '\u{fe4f}'), // CJK Compatibility Forms
+        ('\u{ff00}', '\u{ff0f}'), // Fullwidth punctuation...
+        ('\u{ff1a}', '\u{ff20}'),
+        ('\u{ff3b}', '\u{ff40}'),
+        ('\u{ff5b}', '\u{ff65}'),
+    ];
+
+    !c.is_whitespace()
+        && !punctuation
+            .iter()
+            .any(|&(first, last)| first <= c && c <= last)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharsetMatcher {
+    option: CharsetOption,
+
+    /// Whether each of the first `TABLE_SIZE` characters is in the set
+    table: [bool; TABLE_SIZE as usize],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CharsetError {
+    /// A part that isn't a character, a number or a range of them, e.g. `a-` or `z-a`
+    InvalidPart(String),
+
+    /// The option ends with a comma that isn't itself part of the option, e.g. `a,`
+    TrailingComma,
+}
+
+impl fmt::Display for CharsetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CharsetError::InvalidPart(part) => write!(f, "invalid part '{}'", part),
+            CharsetError::TrailingComma => write!(f, "trailing comma"),
+        }
+    }
+}
+
+/// A character number: either a decimal number, or the character itself (which
+/// means digits can only be given as numbers).
+fn parse_char_number(chars: &[char], index: &mut usize) -> Option<u32> {
+    let first = *chars.get(*index)?;
+    if !first.is_ascii_digit() {
+        *index += 1;
+        return Some(u32::from(first));
+    }
+
+    let digits: String = chars[*index..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    *index += digits.len();
+
+    digits.parse().ok()
+}
+
+impl CharsetMatcher {
+    /// Parses the option the same way Vim does (see `:help isfname`).
+    ///
+    /// Parts are separated by commas, and are interpreted from left to right:
+    ///     a       the character `a`
+    ///     97      the character with that number, so also `a`
+    ///     a-z     a range, and either end can be a number, like `48-57`
+    ///     ^a-z    excludes the range instead
+    ///     @       every alphabetic character, `@-@` is the `@` character itself
+    ///
+    /// A comma where a character is expected is just a comma, so `,,,` adds one and
+    /// `^,,` excludes it. A `^` at the very end adds `^`.
+    pub fn parse(option: CharsetOption, value: &str) -> Result<Self, CharsetError> {
+        let chars: Vec<char> = value.chars().collect();
+        let mut table = [false; TABLE_SIZE as usize];
+
+        let mut index = 0;
+        while index < chars.len() {
+            let part_start = index;
+            let invalid_part = || {
+                let part = chars[part_start..]
+                    .iter()
+                    .take_while(|&&c| c != ',')
+                    .collect::<String>();
+                CharsetError::InvalidPart(part)
+            };
+
+            let exclude = chars[index] == '^' && index + 1 < chars.len();
+            if exclude {
+                index += 1;
+            }
+
+            let start = parse_char_number(&chars, &mut index).ok_or_else(invalid_part)?;
+            let finish = if chars.get(index) == Some(&'-') && index + 1 < chars.len() {
+                index += 1;
+                Some(parse_char_number(&chars, &mut index).ok_or_else(invalid_part)?)
+            } else {
+                None
+            };
+
+            let ends_here = matches!(chars.get(index), None | Some(','));
+            let in_table = |code: u32| code > 0 && code < TABLE_SIZE;
+            if !ends_here || !in_table(start) || finish.is_some_and(|f| f < start || !in_table(f)) {
+                return Err(invalid_part());
+            }
+
+            let (range, only_alphabetic) = match finish {
+                None if start == u32::from('@') => (1..=TABLE_SIZE - 1, true),
+                None => (start..=start, false),
+                Some(finish) => (start..=finish, false),
+            };
+
+            for code in range {
+                let is_alphabetic = std::char::from_u32(code).is_some_and(|c| c.is_alphabetic());
+                if !only_alphabetic || is_alphabetic {
+                    table[code as usize] = !exclude;
+                }
+            }
+
+            // Skip the comma, and any spaces after it
+            if index < chars.len() {
+                index += 1;
+                while chars.get(index) == Some(&' ') {
+                    index += 1;
+                }
+
+                if index == chars.len() {
+                    return Err(CharsetError::TrailingComma);
+                }
+            }
+        }
+
+        Ok(CharsetMatcher { option, table })
+    }
+
+    /// Neovim won't let anyone set an invalid option, but if we get one anyway,
+    /// fall back to the default rather than fall over.
+    pub fn parse_or_default(option: CharsetOption, value: &str) -> Self {
+        Self::parse(option, value).unwrap_or_else(|err| {
+            error!("Invalid '{}' {:?}: {}", option.name(), value, err);
+            Self::default_for(option)
+        })
+    }
+
+    pub fn default_for(option: CharsetOption) -> Self {
+        Self::parse(option, option.default_value()).expect("the default value is valid")
+    }
+
+    pub fn option(&self) -> CharsetOption {
+        self.option
+    }
+
+    pub fn match_char(&self, c: &char) -> bool {
+        match u32::from(*c) {
+            code if code < TABLE_SIZE => self.table[code as usize],
+            _ => self.option.includes_wide(*c),
+        }
+    }
+
+    /// Split a line into runs of characters in the set, e.g. the words Vim would see for 'iskeyword'.
+    pub fn words<'a>(&self, line: &'a str) -> Vec<&'a str> {
+        line.split(|c: char| !self.match_char(&c))
+            .filter(|word| !word.is_empty())
+            .collect()
+    }
+
+    /// The run of characters in the set around `cursor`, as byte offsets into `line`.
+    ///
+    /// `cursor` is a byte column, the way Neovim counts them, and is clamped to the
+    /// line and to a character boundary. `start` is where the run before the cursor begins (which is what
+    /// `complete()` wants), and `finish` is where the run after it ends, so
+    /// `&line[start..finish]` is the whole thing and is empty when there isn't one.
+    pub fn find(&self, line: &str, cursor: u64) -> LineRange {
+        let mut cursor = (cursor as usize).min(line.len());
+        while !line.is_char_boundary(cursor) {
+            cursor -= 1;
+        }
+
+        let start = line[..cursor]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| self.match_char(c))
+            .last()
+            .map_or(cursor, |(index, _)| index);
+
+        let finish = line[cursor..]
+            .char_indices()
+            .take_while(|(_, c)| self.match_char(c))
+            .last()
+            .map_or(cursor, |(index, c)| cursor + index + c.len_utf8());
+
+        LineRange {
+            start,
+            cursor,
+            finish,
+        }
+    }
+}
+
+/// Defaults to 'iskeyword', since that's what almost everything wants.
+impl Default for CharsetMatcher {
+    fn default() -> Self {
+        Self::default_for(CharsetOption::IsKeyword)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keywords(value: &str) -> Result<CharsetMatcher, CharsetError> {
+        CharsetMatcher::parse(CharsetOption::IsKeyword, value)
+    }
+
+    #[test]
+    fn test_grammar() {
+        // (option, characters in it, characters not in it)
+        let cases = vec![
+            ("a-z", "amz", "AZ0-"),
+            ("48-57", "059", "a/:"),
+            ("#-43", "#$+", "\",-"),
+            ("97-z", "az", "`{"),
+            ("-", "-", "a"),
+            ("_,-,a", "_-a", "b,"),
+            ("a-z,^m", "alz", "m"),
+            ("^a-z,#,^", "#^", "az"),
+            ("@", "azAZéÿ", "09_@"),
+            ("@,^a-z", "AZé", "az"),
+            ("a-z,A-Z,@-@", "aZ@", "0"),
+            ("48-57,,,_", "09,_", "a"),
+            (" -~,^,,9", " a~\t", ",\n"),
+            ("a, b", "ab", " "),
+            ("^", "^", "a"),
+            ("@,48-57,_,192-255", "aZ09_é日", "-.,@ "),
+        ];
+
+        for (option, included, excluded) in cases {
+            let matcher =
+                keywords(option).unwrap_or_else(|err| panic!("{:?} should parse: {}", option, err));
+
+            for c in included.chars() {
+                assert!(
+                    matcher.match_char(&c),
+                    "{:?} should include {:?}",
+                    option,
+                    c
+                );
+            }
+            for c in excluded.chars() {
+                assert!(
+                    !matcher.match_char(&c),
+                    "{:?} should exclude {:?}",
+                    option,
+                    c
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let cases = vec![
+            ("a-", CharsetError::InvalidPart(String::from("a-"))),
+            ("z-a", CharsetError::InvalidPart(String::from("z-a"))),
+            ("ab", CharsetError::InvalidPart(String::from("ab"))),
+            ("0", CharsetError::InvalidPart(String::from("0"))),
+            ("300", CharsetError::InvalidPart(String::from("300"))),
+            ("a-300", CharsetError::InvalidPart(String::from("a-300"))),
+            ("a,b,", CharsetError::TrailingComma),
+        ];
+
+        for (option, expected) in cases {
+            assert_eq!(Err(expected), keywords(option), "{:?}", option);
+        }
+
+        // Doesn't panic, just uses the default
+        assert_eq!(
+            CharsetMatcher::default(),
+            CharsetMatcher::parse_or_default(CharsetOption::IsKeyword, "z-a")
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        let options = [
+            CharsetOption::IsKeyword,
+            CharsetOption::IsFname,
+            CharsetOption::IsIdent,
+            CharsetOption::IsPrint,
+        ];
+        for option in options.iter() {
+            assert_eq!(*option, CharsetMatcher::default_for(*option).option());
+        }
+    }
+
+    #[test]
+    fn test_wide_characters_depend_on_the_option() {
+        let wide = |option| CharsetMatcher::default_for(option).match_char(&'日');
+
+        assert!(wide(CharsetOption::IsKeyword));
+        assert!(wide(CharsetOption::IsFname));
+        assert!(wide(CharsetOption::IsPrint));
+        assert!(!wide(CharsetOption::IsIdent));
+
+        let keywords = CharsetMatcher::default_for(CharsetOption::IsKeyword);
+        assert!(!keywords.match_char(&'、'));
+    }
+
+    #[test]
+    fn test_find_a_whole_path() {
+        let isfname = CharsetMatcher::default_for(CharsetOption::IsFname);
+        let iskeyword = CharsetMatcher::default_for(CharsetOption::IsKeyword);
+        let line = "source ~/.config/nvim/ini";
+
+        let range = isfname.find(line, line.len() as u64);
+        assert_eq!("~/.config/nvim/ini", &line[range.start..range.finish]);
+
+        let range = iskeyword.find(line, line.len() as u64);
+        assert_eq!("ini", &line[range.start..range.finish]);
+    }
+}
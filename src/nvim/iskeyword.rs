@@ -1,132 +1,21 @@
-use std::collections::HashSet;
-use std::str::FromStr;
+// 'iskeyword' is the charset most things care about, since it decides what a word is.
 
-use log::{info, trace};
+use super::charset::{CharsetMatcher, CharsetOption};
 
-use crate::collections::LineRange;
+/// What `:set iskeyword?` says in a fresh Neovim, for buffers we haven't heard about yet.
+pub const DEFAULT_ISKEYWORD: &str = "@,48-57,_,192-255";
 
-#[derive(Debug)]
-pub struct KeywordMatcher {
-    contains_at: bool,
-    character_set: HashSet<char>,
-    // TODO: Ignored characters.
-}
-
-#[allow(dead_code)]
-impl KeywordMatcher {
-    pub fn match_char(&self, c: &char) -> bool {
-        // return any(x == c for x in self.character_set)
-        self.character_set.contains(&c)
-            || (self.contains_at && (u32::from(*c) > 255 || c.is_alphabetic()))
-    }
-
-    pub fn find(&self, line: &str, cursor: u64) -> LineRange {
-        let cursor = cursor as usize;
-
-        let mut start = cursor;
-        let mut finish = cursor;
-
-        // TODO(tjdevries): Need to handle multibyte problems here...
-        let char_vec: Vec<char> = line.chars().collect();
-        for index in (0..cursor as usize).rev() {
-            if !self.match_char(&char_vec[index]) {
-                start = index + 1;
-                break;
-            }
-        }
-
-        for index in cursor + 1..line.len() {
-            finish = index;
-            if !self.match_char(&char_vec[index]) {
-                break;
-            }
-        }
-
-        LineRange { start, finish }
-    }
-}
-
-fn convert_numeric_string(numeric_str: &str) -> char {
-    std::char::from_u32(
-        numeric_str
-            .parse::<u32>()
-            .expect(&format!("Already numeric {}", numeric_str)),
-    )
-    .expect("Valid char")
-}
-
-#[derive(Debug)]
-pub enum KeywordError {
-    ContainsTripleCommasPlzNo,
-}
-
-impl FromStr for KeywordMatcher {
-    type Err = KeywordError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.contains(",,,") {
-            return Err(KeywordError::ContainsTripleCommasPlzNo);
-        }
-
-        let mut contains_at = false;
-
-        let mut character_set = HashSet::new();
-        for section in s.split(',') {
-            match section {
-                "@" => {
-                    contains_at = true;
-                }
-                "@-@" => {
-                    character_set.insert('@');
-                }
-                section if section.contains("-") => {
-                    // TODO: OK, so we can split the item on "-"
-                    // Then do the range of the things here.
-                    //  alpha ranges might be hard?...
-                    //  Honestly, we could leave these for later.
-                    //  numeric ranges should be pretty easy.
-                    let subsections: Vec<&str> = section.split("-").collect();
-                    info!("Current subsections: {:?} {:?}", section, subsections);
-
-                    let start = subsections[0].parse::<u32>().expect("Already numeric");
-                    let finish = subsections[1].parse::<u32>().expect("Already numeric");
-
-                    info!("    Start {} / Finish {}", start, finish);
-
-                    for i in start..finish + 1 {
-                        let new_char = std::char::from_u32(i).expect("This has to be valid");
-                        trace!("Found new_char: {}", new_char);
-                        character_set.insert(new_char);
-                    }
-                }
-                section if section.len() > 0 && section.chars().all(|x| x.is_numeric()) => {
-                    character_set.insert(convert_numeric_string(section));
-                }
-                section if section.len() > 0 && section.chars().all(|x| x.is_alphabetic()) => {
-                    section.chars().for_each(|x| {
-                        character_set.insert(x);
-                    });
-                }
-                _ => {
-                    // println!("What is this situation...");
-                }
-            }
-        }
-
-        Ok(KeywordMatcher {
-            contains_at,
-            character_set,
-        })
-    }
-}
+pub type KeywordMatcher = CharsetMatcher;
 
+/// The matcher for a buffer's 'iskeyword', or the default one if it doesn't parse.
 pub fn transform(iskeyword: &str) -> KeywordMatcher {
-    iskeyword.parse().expect("this always works")
+    CharsetMatcher::parse_or_default(CharsetOption::IsKeyword, iskeyword)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::collections::LineRange;
 
     #[test]
     fn test_empty_iskeyword() {
@@ -166,6 +55,22 @@ mod tests {
         assert!(!matcher.match_char(&'A'));
     }
 
+    #[test]
+    fn test_split_into_words() {
+        let matcher = transform(DEFAULT_ISKEYWORD);
+
+        assert_eq!(vec!["foo", "bar"], matcher.words("foo(bar,"));
+        assert_eq!(
+            vec!["hello", "world_2"],
+            matcher.words("\thello\t world_2;")
+        );
+        assert_eq!(vec!["héllo", "日本語"], matcher.words("héllo.日本語"));
+        assert!(matcher.words("  ()  ").is_empty());
+
+        let matcher = transform("@,48-57,_,45");
+        assert_eq!(vec!["foo-bar", "baz"], matcher.words("foo-bar.baz"));
+    }
+
     #[test]
     fn test_find_words() {
         let matcher = transform("65-81,91-116");
@@ -177,8 +82,52 @@ mod tests {
             matcher.find("hello world", 7),
             LineRange {
                 start: 7,
-                finish: 10
+                cursor: 7,
+                finish: 11
             }
         );
     }
+
+    #[test]
+    fn test_find_byte_columns() {
+        fn range(line: &str, cursor: u64) -> (usize, usize, &str) {
+            let LineRange { start, finish, .. } = transform(DEFAULT_ISKEYWORD).find(line, cursor);
+            (start, finish, &line[start..finish])
+        }
+
+        // Typing at the end of a word
+        assert_eq!((4, 7, "foo"), range("let foo", 7));
+        assert_eq!((4, 10, "foobar"), range("let foobar", 7));
+
+        // Nothing there, at either end of the line
+        assert_eq!((0, 0, ""), range("", 0));
+        assert_eq!((0, 0, ""), range(" x", 0));
+        assert_eq!((0, 3, "foo"), range("foo", 0));
+        assert_eq!((4, 4, ""), range("foo ", 4));
+        assert_eq!((4, 4, ""), range("foo ", 40));
+
+        // Multibyte characters count as their bytes
+        assert_eq!((0, 6, "héllo"), range("héllo", 6));
+        assert_eq!((7, 16, "日本語"), range("日本 日本語", 16));
+        assert_eq!((9, 18, "日本語"), range("日本、日本語", 18));
+        assert_eq!((4, 10, "a😀b"), range("(x) a😀b", 10));
+
+        // In the middle of a character goes back to the start of it
+        assert_eq!((0, 6, "héllo"), range("héllo", 2));
+    }
+
+    #[test]
+    fn test_find_typed_part() {
+        fn typed(line: &str, cursor: u64) -> &str {
+            let range = transform(DEFAULT_ISKEYWORD).find(line, cursor);
+            &line[range.start..range.cursor]
+        }
+
+        assert_eq!("fo", typed("let foo", 6));
+        assert_eq!("foo", typed("let foo", 40));
+
+        // Byte 2 is inside `é`, which hasn't been typed past yet
+        assert_eq!("h", typed("héllo", 2));
+        assert_eq!("日", typed("日本語", 4));
+    }
 }
@@ -1 +1,2 @@
+pub mod charset;
 pub mod iskeyword;
@@ -0,0 +1,397 @@
+// Decoding the arguments Lua sends us.
+//
+// Everything comes in as a list of msgpack values, and it's easy to get one
+// wrong from Lua. Instead of panicking (which only shows up in the log), a bad
+// argument turns into an error that says which method and which field it was,
+// and requests send that back to Neovim.
+
+use std::fmt;
+
+use nvim_rs::Value;
+use serde::de::DeserializeOwned;
+
+/// Bumped whenever a method changes what it takes or what it gives back, so that
+/// the Lua side can tell it's talking to a binary that was built from other code.
+pub const PROTOCOL_VERSION: u64 = 3;
+
+/// An enum of RPC methods along with the names Lua calls them by. `NeovimHandler`
+/// dispatches on the enum, and `initialize` lists `ALL` of them, so the methods we
+/// say we have and the ones we handle come from the same place.
+macro_rules! methods {
+    ($(#[$attr:meta])* pub enum $name:ident { $($variant:ident = $method:literal,)* }) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($variant,)*
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant,)*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $method,)*
+                }
+            }
+
+            pub fn names() -> Vec<&'static str> {
+                $name::ALL.iter().map(|method| method.name()).collect()
+            }
+
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($method => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+methods! {
+    /// Every request `NeovimHandler` answers.
+    pub enum Request {
+        Initialize = "initialize",
+        FindStart = "find_start",
+        CompleteSync = "complete_sync",
+        Setup = "setup",
+        BufInitialize = "buf_initialize",
+    }
+}
+
+methods! {
+    /// Every notification `NeovimHandler` does something with.
+    pub enum Notification {
+        Complete = "complete",
+        Cancel = "cancel",
+        BufInitialize = "buf_initialize",
+        BufDetach = "buf_detach",
+        BufWipe = "buf_wipe",
+        BufReload = "buf_reload",
+        BufIskeyword = "buf_iskeyword",
+        BufFiletype = "buf_filetype",
+        BufAttachLines = "buf_attach_lines",
+        BufAttachBytes = "buf_attach_bytes",
+        BufChangedtick = "buf_changedtick",
+        SourceNotify = "source_notify",
+    }
+}
+
+/// What we answer `initialize` with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub version: String,
+    pub protocol: u64,
+    pub sources: Vec<String>,
+}
+
+impl ServerInfo {
+    pub fn new(sources: Vec<String>) -> Self {
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            sources,
+        }
+    }
+}
+
+fn string_list(strings: &[impl AsRef<str>]) -> Value {
+    Value::Array(
+        strings
+            .iter()
+            .map(|string| Value::from(string.as_ref()))
+            .collect(),
+    )
+}
+
+impl From<ServerInfo> for Value {
+    fn from(info: ServerInfo) -> Self {
+        Value::Map(vec![
+            (Value::from("version"), Value::from(info.version)),
+            (Value::from("protocol"), Value::from(info.protocol)),
+            (Value::from("sources"), string_list(&info.sources)),
+            (Value::from("requests"), string_list(&Request::names())),
+            (
+                Value::from("notifications"),
+                string_list(&Notification::names()),
+            ),
+        ])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError {
+    /// The RPC method that was called
+    pub method: String,
+
+    /// Which argument was wrong, e.g. `bufnr` or `context.cursor`
+    pub field: String,
+
+    pub message: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: bad `{}`: {}", self.method, self.field, self.message)
+    }
+}
+
+impl From<DecodeError> for Value {
+    fn from(err: DecodeError) -> Self {
+        Value::from(err.to_string())
+    }
+}
+
+/// The entries of a table. Lua can't tell an empty table from an empty list, and
+/// sends a list, so that counts as an empty table too.
+pub fn as_table(value: &Value) -> Option<&[(Value, Value)]> {
+    match value {
+        Value::Map(map) => Some(map),
+        Value::Array(list) if list.is_empty() => Some(&[]),
+        _ => None,
+    }
+}
+
+fn decode<T: DeserializeOwned>(method: &str, field: &str, value: &Value) -> Result<T, DecodeError> {
+    rmpv::ext::from_value(value.clone()).map_err(|err| DecodeError {
+        method: method.to_string(),
+        field: field.to_string(),
+        message: err.to_string(),
+    })
+}
+
+/// The positional arguments of one call.
+#[derive(Debug)]
+pub struct Args<'a> {
+    method: &'a str,
+    args: &'a [Value],
+}
+
+impl<'a> Args<'a> {
+    pub fn new(method: &'a str, args: &'a [Value]) -> Self {
+        Args { method, args }
+    }
+
+    /// An error about one of these arguments, for checks that go past the types.
+    pub fn error(&self, field: &str, message: impl Into<String>) -> DecodeError {
+        DecodeError {
+            method: self.method.to_string(),
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// The argument at `index`, which has to be there.
+    pub fn get<T: DeserializeOwned>(&self, index: usize, name: &str) -> Result<T, DecodeError> {
+        match self.args.get(index) {
+            Some(value) => decode(self.method, name, value),
+            None => Err(self.error(name, format!("missing argument {}", index + 1))),
+        }
+    }
+
+    /// The argument at `index`, where leaving it out (or sending `nil`) gives `None`.
+    pub fn optional<T: DeserializeOwned>(
+        &self,
+        index: usize,
+        name: &str,
+    ) -> Result<Option<T>, DecodeError> {
+        match self.args.get(index) {
+            None | Some(Value::Nil) => Ok(None),
+            Some(value) => decode(self.method, name, value).map(Some),
+        }
+    }
+
+    /// A map argument, to decode field by field.
+    pub fn fields(&self, index: usize, name: &'a str) -> Result<Fields<'a>, DecodeError> {
+        let value = self
+            .args
+            .get(index)
+            .ok_or_else(|| self.error(name, format!("missing argument {}", index + 1)))?;
+
+        match as_table(value) {
+            Some(map) => Ok(Fields {
+                method: self.method,
+                name,
+                map,
+            }),
+            None => Err(self.error(name, format!("expected a table, got {}", value))),
+        }
+    }
+
+    /// Like `fields`, but `nil` or leaving it out is fine too.
+    pub fn optional_fields(
+        &self,
+        index: usize,
+        name: &'a str,
+    ) -> Result<Option<Fields<'a>>, DecodeError> {
+        match self.args.get(index) {
+            None | Some(Value::Nil) => Ok(None),
+            Some(_) => self.fields(index, name).map(Some),
+        }
+    }
+
+    /// Everything from `index` on, as is.
+    pub fn rest(&self, index: usize) -> Vec<Value> {
+        self.args.iter().skip(index).cloned().collect()
+    }
+}
+
+/// The fields of a table argument.
+#[derive(Debug)]
+pub struct Fields<'a> {
+    method: &'a str,
+    name: &'a str,
+    map: &'a [(Value, Value)],
+}
+
+impl<'a> Fields<'a> {
+    fn field_name(&self, key: &str) -> String {
+        format!("{}.{}", self.name, key)
+    }
+
+    fn lookup(&self, key: &str) -> Option<&'a Value> {
+        self.map
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn error(&self, key: &str, message: impl Into<String>) -> DecodeError {
+        DecodeError {
+            method: self.method.to_string(),
+            field: self.field_name(key),
+            message: message.into(),
+        }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, DecodeError> {
+        match self.lookup(key) {
+            Some(value) => decode(self.method, &self.field_name(key), value),
+            None => Err(self.error(key, "missing")),
+        }
+    }
+
+    pub fn optional<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, DecodeError> {
+        match self.lookup(key) {
+            None | Some(Value::Nil) => Ok(None),
+            Some(value) => decode(self.method, &self.field_name(key), value).map(Some),
+        }
+    }
+
+    /// Every entry, for tables that are keyed by name, with the keys checked to be strings.
+    pub fn entries(&self) -> Result<Vec<(String, &'a Value)>, DecodeError> {
+        self.map
+            .iter()
+            .map(|(key, value)| match key.as_str() {
+                Some(key) => Ok((key.to_string(), value)),
+                None => Err(DecodeError {
+                    method: self.method.to_string(),
+                    field: self.name.to_string(),
+                    message: format!("keys should be strings, got {}", key),
+                }),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::map;
+
+    #[test]
+    fn test_server_info() {
+        let info = Value::from(ServerInfo::new(vec![String::from("buffer")]));
+        let lookup = |key: &str| {
+            info.as_map()
+                .unwrap()
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, val)| val.clone())
+                .unwrap()
+        };
+
+        assert_eq!(Value::from(PROTOCOL_VERSION), lookup("protocol"));
+        assert_eq!(Value::from(env!("CARGO_PKG_VERSION")), lookup("version"));
+        assert_eq!(Value::Array(vec![Value::from("buffer")]), lookup("sources"));
+        assert!(lookup("requests")
+            .as_array()
+            .unwrap()
+            .contains(&Value::from("initialize")));
+    }
+
+    #[test]
+    fn test_method_names() {
+        for request in Request::ALL {
+            assert_eq!(Some(*request), Request::from_name(request.name()));
+        }
+        for notification in Notification::ALL {
+            assert_eq!(
+                Some(*notification),
+                Notification::from_name(notification.name())
+            );
+        }
+
+        assert_eq!(None, Request::from_name("complete"));
+        assert_eq!(
+            Some(Notification::Complete),
+            Notification::from_name("complete")
+        );
+    }
+
+    #[test]
+    fn test_positional_args() {
+        let values = vec![Value::from(1), Value::from("hello")];
+        let args = Args::new("method", &values);
+
+        assert_eq!(Ok(1), args.get::<u64>(0, "bufnr"));
+        assert_eq!(Ok(String::from("hello")), args.get::<String>(1, "line"));
+        assert_eq!(Ok(None), args.optional::<String>(2, "source"));
+
+        let err = args.get::<u64>(1, "cursor").unwrap_err();
+        assert_eq!("method", err.method);
+        assert_eq!("cursor", err.field);
+
+        let err = args.get::<u64>(2, "cursor").unwrap_err();
+        assert_eq!("method: bad `cursor`: missing argument 3", err.to_string());
+    }
+
+    #[test]
+    fn test_fields() {
+        let values = vec![map(vec![
+            ("word", Value::from("hel")),
+            ("cursor", Value::Array(vec![Value::from(1), Value::from(2)])),
+            ("bufnr", Value::from("one")),
+        ])];
+        let args = Args::new("complete_sync", &values);
+        let fields = args.fields(0, "context").unwrap();
+
+        assert_eq!(Ok(String::from("hel")), fields.get::<String>("word"));
+        assert_eq!(Ok(Some((1, 2))), fields.optional::<(u64, u64)>("cursor"));
+        assert_eq!(Ok(None), fields.optional::<String>("cwd"));
+
+        let err = fields.get::<u64>("bufnr").unwrap_err();
+        assert_eq!("context.bufnr", err.field);
+
+        let err = fields.get::<String>("cwd").unwrap_err();
+        assert_eq!("complete_sync: bad `context.cwd`: missing", err.to_string());
+
+        assert!(args.fields(1, "sources").is_err());
+        assert!(args.optional_fields(1, "sources").unwrap().is_none());
+
+        let values = vec![Value::Array(Vec::new()), Value::from(1)];
+        let args = Args::new("complete_sync", &values);
+        assert!(args
+            .fields(0, "sources")
+            .unwrap()
+            .entries()
+            .unwrap()
+            .is_empty());
+
+        let err = args.fields(1, "sources").unwrap_err();
+        assert_eq!(
+            "complete_sync: bad `sources`: expected a table, got 1",
+            err.to_string()
+        );
+    }
+}
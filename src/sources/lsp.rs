@@ -0,0 +1,360 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::info;
+use nvim_rs::Value;
+use tokio::sync::RwLock;
+
+use super::{CompletionItem, CompletionSource, Completions, OptionKind, OptionSpec, SourceOptions};
+use crate::{
+    fuzzy,
+    lsp::{CompletionItemKind, CompletionList, Position},
+    CompletionContext,
+};
+
+/// The last `textDocument/completion` response we got for a buffer.
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    /// Where the completion was requested
+    position: Position,
+
+    /// The word that was being completed when the request was sent
+    word: String,
+
+    list: CompletionList,
+}
+
+impl CachedResponse {
+    /// Typing more of the same word keeps us at the same position,
+    /// so the cached items are still good to filter from.
+    fn matches(&self, ctx: &CompletionContext) -> bool {
+        let same_line = ctx
+            .cursor
+            .is_none_or(|cursor| cursor.line == self.position.line);
+
+        same_line && ctx.word.starts_with(&self.word)
+    }
+}
+
+/// Completes using Neovim's builtin LSP client.
+///
+/// We don't talk to language servers ourselves. Instead, the Lua side sends
+/// `textDocument/completion` and forwards whatever comes back with the
+/// `completion_response` notification, and we filter from that until it's stale.
+///
+/// Options:
+///     types       list of `CompletionItemKind` names to keep, e.g. `{'functions', 'classes'}`
+#[derive(Default)]
+pub struct LspCompletionSource {
+    cache: RwLock<HashMap<u64, CachedResponse>>,
+}
+
+impl Debug for LspCompletionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LspCompletionSource")
+            .finish_non_exhaustive()
+    }
+}
+
+impl LspCompletionSource {
+    pub async fn on_response(
+        &self,
+        bufnr: u64,
+        position: Position,
+        word: String,
+        response: Value,
+    ) -> Result<()> {
+        let list = CompletionList::from_response(response)?;
+        info!(
+            "LSP response for {} at {:?}: {} items (incomplete: {})",
+            bufnr,
+            position,
+            list.items.len(),
+            list.is_incomplete
+        );
+
+        let mut cache = self.cache.write().await;
+        match cache.get_mut(&bufnr) {
+            // More than one client can answer for the same request
+            Some(cached) if cached.position == position && cached.word == word => {
+                cached.list.is_incomplete |= list.is_incomplete;
+                cached.list.items.extend(list.items);
+            }
+            _ => {
+                cache.insert(
+                    bufnr,
+                    CachedResponse {
+                        position,
+                        word,
+                        list,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the `types` option into the set of kinds to keep. `None` means keep everything.
+fn parse_types(opts: &SourceOptions) -> Result<Option<HashSet<CompletionItemKind>>> {
+    opts.get("types").map(types_from_value).transpose()
+}
+
+fn types_from_value(types: &Value) -> Result<HashSet<CompletionItemKind>> {
+    let types = types
+        .as_array()
+        .ok_or_else(|| anyhow!("lsp: `types` must be a list of kind names"))?;
+
+    types
+        .iter()
+        .map(|name| {
+            let name = name
+                .as_str()
+                .ok_or_else(|| anyhow!("lsp: `types` must be a list of kind names"))?;
+
+            CompletionItemKind::from_name(name)
+                .ok_or_else(|| anyhow!("lsp: unknown type '{}'", name))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl CompletionSource for LspCompletionSource {
+    fn name(&self) -> &'static str {
+        "lsp"
+    }
+
+    /// The server knows what the words are, so its items win over the same word from the buffer
+    fn priority(&self) -> i64 {
+        20
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        fn check_types(types: &Value) -> Result<()> {
+            types_from_value(types).map(|_| ())
+        }
+
+        vec![OptionSpec::optional(
+            "types",
+            OptionKind::Custom(check_types),
+        )]
+    }
+
+    async fn complete(&self, ctx: &CompletionContext, opts: &SourceOptions) -> Result<Completions> {
+        let types = parse_types(opts)?;
+
+        let cache = self.cache.read().await;
+        let cached = match cache.get(&ctx.bufnr) {
+            Some(cached) if cached.matches(ctx) => cached,
+            _ => {
+                // Nothing useful yet, ask Lua to go get some
+                return Ok(Completions {
+                    items: Vec::new(),
+                    is_incomplete: true,
+                });
+            }
+        };
+
+        let items = cached
+            .list
+            .items
+            .iter()
+            .filter(|item| match (&types, item.kind) {
+                (None, _) => true,
+                (Some(types), Some(kind)) => types.contains(&kind),
+                (Some(_), None) => false,
+            })
+            .filter_map(|item| {
+                let score = fuzzy::score(&ctx.word, item.filter_text())?;
+                Some(CompletionItem {
+                    score,
+                    ..CompletionItem::from(item.clone())
+                })
+            })
+            .collect();
+
+        Ok(Completions {
+            items,
+            // The server told us the list changes as you type, so once you have,
+            // it's time to ask again.
+            is_incomplete: cached.list.is_incomplete && cached.word != ctx.word,
+        })
+    }
+
+    async fn on_detach(&self, bufnr: u64) {
+        self.cache.write().await.remove(&bufnr);
+    }
+
+    async fn on_notify(&self, method: &str, args: Vec<Value>) -> Result<()> {
+        match method {
+            "completion_response" => {
+                let mut args = args.into_iter();
+                let bufnr = args
+                    .next()
+                    .and_then(|val| val.as_u64())
+                    .ok_or_else(|| anyhow!("lsp: completion_response needs a bufnr"))?;
+                let position = args
+                    .next()
+                    .ok_or_else(|| anyhow!("lsp: completion_response needs a position"))?;
+                let position: Position = rmpv::ext::from_value(position)?;
+                let word = args
+                    .next()
+                    .and_then(|val| val.as_str().map(String::from))
+                    .unwrap_or_default();
+                let response = args.next().unwrap_or(Value::Nil);
+
+                self.on_response(bufnr, position, word, response).await
+            }
+            _ => Err(anyhow!("lsp: unknown notification '{}'", method)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::map, Cursor};
+
+    fn item(label: &str, kind: u64) -> Value {
+        map(vec![
+            ("label", Value::from(label)),
+            ("kind", Value::from(kind)),
+        ])
+    }
+
+    fn ctx(word: &str) -> CompletionContext {
+        CompletionContext {
+            cursor: Some(Cursor { line: 0, col: 0 }),
+            ..CompletionContext::for_test(word)
+        }
+    }
+
+    fn words(completions: &Completions) -> Vec<&str> {
+        completions
+            .items
+            .iter()
+            .map(|item| item.word.as_str())
+            .collect()
+    }
+
+    async fn source_with(is_incomplete: bool) -> LspCompletionSource {
+        let source = LspCompletionSource::default();
+        let response = map(vec![
+            ("isIncomplete", Value::from(is_incomplete)),
+            (
+                "items",
+                Value::Array(vec![
+                    item("print", 3),
+                    item("printer", 7),
+                    item("private_value", 6),
+                ]),
+            ),
+        ]);
+
+        source
+            .on_response(
+                1,
+                Position {
+                    line: 0,
+                    character: 2,
+                },
+                String::from("pr"),
+                response,
+            )
+            .await
+            .unwrap();
+
+        source
+    }
+
+    #[tokio::test]
+    async fn test_nothing_cached_asks_for_more() {
+        let source = LspCompletionSource::default();
+        let completions = source
+            .complete(&ctx("pr"), &SourceOptions::default())
+            .await
+            .unwrap();
+
+        assert!(completions.items.is_empty());
+        assert!(completions.is_incomplete);
+    }
+
+    #[tokio::test]
+    async fn test_filters_cached_items_as_you_type() {
+        let source = source_with(false).await;
+
+        let completions = source
+            .complete(&ctx("pri"), &SourceOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["print", "printer", "private_value"],
+            words(&completions)
+        );
+        assert!(!completions.is_incomplete);
+
+        // A different word or line means the cache is for somewhere else
+        let completions = source
+            .complete(&ctx("x"), &SourceOptions::default())
+            .await
+            .unwrap();
+        assert!(completions.is_incomplete);
+
+        let other_line = CompletionContext {
+            cursor: Some(Cursor { line: 3, col: 0 }),
+            ..ctx("pri")
+        };
+        let completions = source
+            .complete(&other_line, &SourceOptions::default())
+            .await
+            .unwrap();
+        assert!(completions.is_incomplete);
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_lists_are_requeried() {
+        let source = source_with(true).await;
+
+        let completions = source
+            .complete(&ctx("pr"), &SourceOptions::default())
+            .await
+            .unwrap();
+        assert!(!completions.is_incomplete);
+
+        let completions = source
+            .complete(&ctx("pri"), &SourceOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["print", "printer", "private_value"],
+            words(&completions)
+        );
+        assert!(completions.is_incomplete);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_types() {
+        let source = source_with(false).await;
+
+        let opts = map(vec![(
+            "types",
+            Value::Array(vec![Value::from("functions"), Value::from("classes")]),
+        )]);
+        let opts = SourceOptions::parse("lsp", &source.options(), &opts).unwrap();
+        let completions = source.complete(&ctx("pr"), &opts).await.unwrap();
+        assert_eq!(vec!["print", "printer"], words(&completions));
+
+        let opts = map(vec![("types", Value::Array(vec![Value::from("classes")]))]);
+        let opts = SourceOptions::parse("lsp", &source.options(), &opts).unwrap();
+        let completions = source.complete(&ctx("pr"), &opts).await.unwrap();
+        assert_eq!(vec!["printer"], words(&completions));
+
+        let opts = map(vec![("types", Value::Array(vec![Value::from("gadgets")]))]);
+        assert!(SourceOptions::parse("lsp", &source.options(), &opts).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+// Putting the results from every source together into the one list Vim shows.
+//
+// The same word often comes from more than one source (a function name is in
+// the buffer and comes from the LSP too), so only one of them is kept: the one
+// from the source with the higher priority. Priority is also added to each
+// item's score, so a source you trust more ranks higher overall. Then each
+// source is capped, and the whole list is, and ties are broken by the word and
+// the order the sources were asked in, so the same request always gives back
+// the same list.
+
+use std::collections::HashMap;
+
+use super::{CompletionItem, SourceOptions};
+
+/// How many items the whole list is cut down to, unless the policy says otherwise.
+pub const DEFAULT_MAX_ITEMS: usize = 100;
+
+/// Applies to the merged list as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergePolicy {
+    pub max_items: usize,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy {
+            max_items: DEFAULT_MAX_ITEMS,
+        }
+    }
+}
+
+/// How much one source counts for, from its own default and what the request asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceWeight {
+    /// Added to the score of every item, and decides who keeps a word both sources have
+    pub priority: i64,
+
+    /// At most this many items from this source make it into the list
+    pub max_items: Option<usize>,
+}
+
+impl SourceWeight {
+    /// The request can change `priority` and set `max_items`, e.g. `buffer = { priority = 5 }`.
+    pub fn new(priority: i64, opts: &SourceOptions) -> Self {
+        SourceWeight {
+            priority: opts.integer("priority").unwrap_or(priority),
+            max_items: opts.count("max_items").map(|max_items| max_items as usize),
+        }
+    }
+}
+
+/// What one source came back with.
+#[derive(Debug)]
+pub struct SourceResults {
+    pub weight: SourceWeight,
+    pub items: Vec<CompletionItem>,
+}
+
+/// One list out of every source's results. Sources are expected in the order they were asked.
+pub fn merge(results: Vec<SourceResults>, policy: &MergePolicy) -> Vec<CompletionItem> {
+    // word -> (source index, item)
+    let mut by_word: HashMap<String, (usize, CompletionItem)> = HashMap::new();
+
+    for (index, source) in results.iter().enumerate() {
+        for item in &source.items {
+            let wins = match by_word.get(&item.word) {
+                Some((kept_index, kept)) => {
+                    let kept_priority = results[*kept_index].weight.priority;
+                    (source.weight.priority, item.score) > (kept_priority, kept.score)
+                }
+                None => true,
+            };
+
+            if wins {
+                by_word.insert(item.word.clone(), (index, item.clone()));
+            }
+        }
+    }
+
+    let mut ranked: Vec<(i64, usize, CompletionItem)> = by_word
+        .into_iter()
+        .map(|(_, (index, item))| (item.score + results[index].weight.priority, index, item))
+        .collect();
+
+    ranked.sort_by(|(a_rank, a_index, a), (b_rank, b_index, b)| {
+        b_rank
+            .cmp(a_rank)
+            .then_with(|| a.word.len().cmp(&b.word.len()))
+            .then_with(|| a.word.cmp(&b.word))
+            .then_with(|| a_index.cmp(b_index))
+    });
+
+    let mut taken = vec![0; results.len()];
+    ranked
+        .into_iter()
+        .filter(|(_, index, _)| {
+            let cap = results[*index].weight.max_items.unwrap_or(usize::MAX);
+            if taken[*index] < cap {
+                taken[*index] += 1;
+                true
+            } else {
+                false
+            }
+        })
+        .take(policy.max_items)
+        .map(|(_, _, item)| item)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::options::common_options;
+    use nvim_rs::Value;
+
+    fn item(word: &str, score: i64, menu: &str) -> CompletionItem {
+        CompletionItem {
+            score,
+            menu: Some(String::from(menu)),
+            ..CompletionItem::new(word)
+        }
+    }
+
+    fn results(priority: i64, items: Vec<CompletionItem>) -> SourceResults {
+        SourceResults {
+            weight: SourceWeight {
+                priority,
+                max_items: None,
+            },
+            items,
+        }
+    }
+
+    fn words(items: &[CompletionItem]) -> Vec<(&str, &str)> {
+        items
+            .iter()
+            .map(|item| (item.word.as_str(), item.menu.as_deref().unwrap_or("")))
+            .collect()
+    }
+
+    #[test]
+    fn test_higher_priority_keeps_the_word() {
+        let merged = merge(
+            vec![
+                results(0, vec![item("print", 90, "[B]"), item("prim", 80, "[B]")]),
+                results(20, vec![item("print", 50, "[L]")]),
+            ],
+            &MergePolicy::default(),
+        );
+
+        assert_eq!(vec![("prim", "[B]"), ("print", "[L]")], words(&merged));
+    }
+
+    #[test]
+    fn test_priority_is_added_to_the_score() {
+        let merged = merge(
+            vec![
+                results(0, vec![item("alpha", 50, "[B]")]),
+                results(20, vec![item("beta", 40, "[L]")]),
+            ],
+            &MergePolicy::default(),
+        );
+
+        assert_eq!(vec![("beta", "[L]"), ("alpha", "[B]")], words(&merged));
+    }
+
+    #[test]
+    fn test_same_priority_keeps_the_better_score_then_the_first_source() {
+        let merged = merge(
+            vec![
+                results(0, vec![item("foo", 10, "a"), item("bar", 10, "a")]),
+                results(0, vec![item("foo", 20, "b"), item("bar", 10, "b")]),
+            ],
+            &MergePolicy::default(),
+        );
+
+        assert_eq!(vec![("foo", "b"), ("bar", "a")], words(&merged));
+    }
+
+    #[test]
+    fn test_ties_are_ordered_by_word() {
+        let merged = merge(
+            vec![results(
+                0,
+                vec![item("bb", 0, ""), item("ccc", 0, ""), item("aa", 0, "")],
+            )],
+            &MergePolicy::default(),
+        );
+
+        assert_eq!(
+            vec!["aa", "bb", "ccc"],
+            words(&merged)
+                .into_iter()
+                .map(|(w, _)| w)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_caps() {
+        let mut buffer = results(
+            0,
+            vec![item("a1", 30, ""), item("a2", 20, ""), item("a3", 10, "")],
+        );
+        buffer.weight.max_items = Some(2);
+        let file = results(0, vec![item("b1", 25, ""), item("b2", 15, "")]);
+
+        let merged = merge(vec![buffer, file], &MergePolicy { max_items: 3 });
+        let merged: Vec<&str> = merged.iter().map(|item| item.word.as_str()).collect();
+        assert_eq!(vec!["a1", "b1", "a2"], merged);
+    }
+
+    #[test]
+    fn test_weight_from_opts() {
+        let opts = Value::Map(vec![
+            (Value::from("priority"), Value::from(-5)),
+            (Value::from("max_items"), Value::from(3)),
+        ]);
+        let opts = SourceOptions::parse("buffer", &common_options(), &opts).unwrap();
+        assert_eq!(
+            SourceWeight {
+                priority: -5,
+                max_items: Some(3)
+            },
+            SourceWeight::new(10, &opts)
+        );
+
+        assert_eq!(
+            SourceWeight {
+                priority: 10,
+                max_items: None
+            },
+            SourceWeight::new(10, &SourceOptions::default())
+        );
+    }
+}
@@ -0,0 +1,1344 @@
+// Resources:
+//
+// - https://rust-analyzer.github.io/rust-analyzer/ide/struct.CompletionItem.html
+// - https://microsoft.github.io/language-server-protocol/specifications/specification-current/#textDocument_completion
+//
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Debug},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    fuzzy,
+    nvim::{
+        charset::{CharsetMatcher, CharsetOption},
+        iskeyword::KeywordMatcher,
+    },
+    word_store::BufferWordStore,
+    CompletionContext,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::future::{self, AbortHandle, Aborted};
+use log::{error, info, trace, warn};
+use nvim_rs::Value;
+use tokio::{fs, sync::RwLock, time};
+
+mod lsp;
+mod merge;
+mod options;
+
+pub use lsp::LspCompletionSource;
+pub use merge::MergePolicy;
+use merge::{SourceResults, SourceWeight};
+pub use options::SourceOptions;
+use options::{OptionKind, OptionSpec};
+
+// CompletionSource: function(ctx) -> Completions
+//
+// FileCompletionSource implements CompletionSource
+
+#[derive(Debug, Default)]
+pub struct Completions {
+    pub items: Vec<CompletionItem>,
+
+    /// The source knows these aren't all the results, and should be asked
+    /// to refresh itself (see `rofl.refresh` on the Lua side).
+    pub is_incomplete: bool,
+}
+
+///                         *complete-items*
+/// Each list item can either be a string or a Dictionary.  When it is a string it
+/// is used as the completion.  When it is a Dictionary it can contain these
+/// items:
+///     word        the text that will be inserted, mandatory
+///
+///     abbr        abbreviation of "word"; when not empty it is used in
+///                 the menu instead of "word"
+///
+///     menu        extra text for the popup menu, displayed after "word"
+///                 or "abbr"
+///
+///     info        more information about the item, can be displayed in a
+///                 preview window
+///
+///     kind        single letter indicating the type of completion
+///
+///     icase       when non-zero case is to be ignored when comparing
+///                 items to be equal; when omitted zero is used, thus
+///                 items that only differ in case are added
+///
+///     equal       when non-zero, always treat this item to be equal when
+///                 comparing. Which means, "equal=1" disables filtering
+///                 of this item.
+///
+///     dup         when non-zero this match will be added even when an
+///                 item with the same word is already present.
+///
+///     empty       when non-zero this match will be added even when it is
+///                 an empty string
+///
+///     user_data   custom data which is associated with the item and
+///                 available in |v:completed_item|; it can be any type;
+///                 defaults to an empty string
+///
+/// Sources put a tag for themselves in `menu` (`[B]` for buffer, `[F]` for file)
+/// so you can tell where an item came from in the popup menu.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionItem {
+    pub word: String,
+    pub abbr: Option<String>,
+    pub menu: Option<String>,
+    pub info: Option<String>,
+    pub kind: Option<String>,
+    pub icase: bool,
+    pub equal: bool,
+    pub dup: bool,
+    pub empty: bool,
+    pub user_data: Option<Value>,
+
+    /// How well this item matched what was typed (see `fuzzy::score`).
+    /// Only used for sorting, Vim never sees it.
+    pub score: i64,
+}
+
+impl CompletionItem {
+    pub fn new(word: impl Into<String>) -> Self {
+        CompletionItem {
+            word: word.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Turns the item into the Dictionary that `complete()` expects.
+///
+/// Only the fields that are actually set get sent, so Vim can fill in its own defaults.
+impl From<CompletionItem> for Value {
+    fn from(item: CompletionItem) -> Self {
+        let mut dict = vec![(Value::from("word"), Value::from(item.word))];
+
+        let strings = vec![
+            ("abbr", item.abbr),
+            ("menu", item.menu),
+            ("info", item.info),
+            ("kind", item.kind),
+        ];
+        for (key, val) in strings {
+            if let Some(val) = val {
+                dict.push((Value::from(key), Value::from(val)));
+            }
+        }
+
+        let flags = vec![
+            ("icase", item.icase),
+            ("equal", item.equal),
+            ("dup", item.dup),
+            ("empty", item.empty),
+        ];
+        for (key, val) in flags {
+            if val {
+                dict.push((Value::from(key), Value::from(1)));
+            }
+        }
+
+        if let Some(user_data) = item.user_data {
+            dict.push((Value::from("user_data"), user_data));
+        }
+
+        Value::Map(dict)
+    }
+}
+
+/// How long a source gets to produce results before we give up on it,
+/// unless it asks for something different.
+pub const DEFAULT_SOURCE_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[async_trait]
+pub trait CompletionSource: Debug + Send + Sync {
+    /// The name used to request this source from Lua, e.g. `sources = { buffer = true }`
+    fn name(&self) -> &'static str;
+
+    /// The characters that make up what this source completes, which decides
+    /// where `find_start` says the completion starts.
+    fn charset(&self) -> CharsetOption {
+        CharsetOption::IsKeyword
+    }
+
+    /// Added to the score of this source's items, and decides which source keeps a
+    /// word more than one of them came up with. Requests can override it with `priority`.
+    fn priority(&self) -> i64 {
+        0
+    }
+
+    /// The deadline for `complete`, unless the request sets `timeout_ms`.
+    /// Results that show up after this are dropped.
+    fn timeout(&self) -> Duration {
+        DEFAULT_SOURCE_TIMEOUT
+    }
+
+    /// The options a request can pass this source, on top of the ones every source takes
+    /// (`priority`, `max_items` and `timeout_ms`). Anything else in the request is an error.
+    fn options(&self) -> Vec<OptionSpec> {
+        Vec::new()
+    }
+
+    /// `opts` is what was passed for this source in the request, e.g. `lsp = { types = ... }`,
+    /// already checked against `options` and with the defaults filled in.
+    async fn complete(&self, ctx: &CompletionContext, opts: &SourceOptions) -> Result<Completions>;
+
+    /// Lines `start_line..final_line` (0-indexed, end exclusive) were replaced by `lines`.
+    async fn on_lines(&self, _bufnr: u64, _start_line: u64, _final_line: u64, _lines: &[String]) {}
+
+    /// The buffer is gone (or we stopped watching it), so anything kept for it can go too.
+    async fn on_detach(&self, _bufnr: u64) {}
+
+    /// Handle a notification sent to this source specifically, via `source_notify`.
+    async fn on_notify(&self, method: &str, _args: Vec<Value>) -> Result<()> {
+        Err(anyhow!(
+            "{} does not handle notification '{}'",
+            self.name(),
+            method
+        ))
+    }
+}
+
+pub type SharedSource = Arc<dyn CompletionSource>;
+
+/// Aborts the source tasks of a `SourceRegistry::complete` that's been dropped.
+struct AbortOnDrop(Vec<AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceEntry {
+    pub source: SharedSource,
+
+    /// Used when the request doesn't set `timeout_ms`
+    pub timeout: Duration,
+}
+
+/// The result of asking several sources for completions at once.
+#[derive(Debug)]
+pub struct GatheredCompletions {
+    pub completions: Completions,
+
+    /// Sources that missed their deadline, so their results were dropped
+    pub timed_out: Vec<String>,
+
+    /// Sources that returned an error instead of completions
+    pub failed: Vec<(String, String)>,
+
+    /// Sources that said their results are incomplete and want to refresh
+    pub incomplete: Vec<String>,
+}
+
+/// All of the completion sources that the server knows about, keyed by name.
+///
+/// Sources get registered once at startup, and then requests can pick
+/// whichever of them they want by name.
+#[derive(Debug, Clone, Default)]
+pub struct SourceRegistry {
+    sources: BTreeMap<String, SourceEntry>,
+}
+
+impl SourceRegistry {
+    pub fn register<S>(&mut self, source: S)
+    where
+        S: CompletionSource + 'static,
+    {
+        let name = source.name().to_string();
+        let timeout = source.timeout();
+        self.sources.insert(
+            name,
+            SourceEntry {
+                source: Arc::new(source),
+                timeout,
+            },
+        );
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SourceEntry> {
+        self.sources.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sources.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.sources.keys().map(|name| name.as_str()).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &SourceEntry)> {
+        self.sources.iter()
+    }
+
+    /// Check what a request sent for source `name`, and fill in its defaults.
+    pub fn options(&self, name: &str, opts: &Value) -> Result<SourceOptions> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown completion source: {}", name))?;
+
+        let mut specs = options::common_options();
+        specs.extend(entry.source.options());
+        SourceOptions::parse(name, &specs, opts)
+    }
+
+    /// Run every named source concurrently, each with its own deadline, and merge
+    /// what they come back with (see `merge`).
+    ///
+    /// A slow source only loses its own results, it never holds up the others.
+    pub async fn complete(
+        &self,
+        requested: &[(String, SourceOptions)],
+        ctx: Arc<CompletionContext>,
+        policy: &MergePolicy,
+    ) -> GatheredCompletions {
+        let mut gathered = GatheredCompletions {
+            completions: Completions::default(),
+            timed_out: Vec::new(),
+            failed: Vec::new(),
+            incomplete: Vec::new(),
+        };
+
+        let mut handles = Vec::new();
+        for (name, opts) in requested {
+            let entry = match self.get(name) {
+                Some(entry) => entry.clone(),
+                None => continue,
+            };
+
+            let weight = SourceWeight::new(entry.source.priority(), opts);
+            let timeout = opts
+                .count("timeout_ms")
+                .map_or(entry.timeout, Duration::from_millis);
+            let ctx = ctx.clone();
+            let opts = opts.clone();
+            let (task, abort) = future::abortable(async move {
+                time::timeout(timeout, entry.source.complete(&ctx, &opts)).await
+            });
+
+            handles.push((name.clone(), weight, tokio::spawn(task), abort));
+        }
+
+        // If whoever asked gives up on these (e.g. the request was cancelled),
+        // the sources shouldn't keep working on them in the background
+        let _abort = AbortOnDrop(
+            handles
+                .iter()
+                .map(|(_, _, _, abort)| abort.clone())
+                .collect(),
+        );
+
+        let mut results = Vec::new();
+        for (name, weight, handle, _) in handles {
+            match handle.await {
+                Ok(Ok(Ok(Ok(c)))) => {
+                    info!("Adding {} completions", name);
+                    if c.is_incomplete {
+                        gathered.incomplete.push(name.clone());
+                    }
+
+                    // Let Lua know where an item came from, e.g. in `v:completed_item`
+                    let items = c
+                        .items
+                        .into_iter()
+                        .map(|mut item| {
+                            item.user_data.get_or_insert_with(|| {
+                                Value::Map(vec![(
+                                    Value::from("source"),
+                                    Value::from(name.as_str()),
+                                )])
+                            });
+                            item
+                        })
+                        .collect();
+
+                    results.push(SourceResults { weight, items });
+                }
+                Ok(Ok(Ok(Err(err)))) => {
+                    warn!("Source {} failed: {}", name, err);
+                    gathered.failed.push((name, err.to_string()));
+                }
+                Ok(Ok(Err(_))) => {
+                    warn!("Source {} missed its deadline, dropping its results", name);
+                    gathered.timed_out.push(name);
+                }
+                Ok(Err(Aborted)) => {
+                    info!("Source {} was aborted", name);
+                }
+                Err(err) => {
+                    error!("Source {} task did not finish: {}", name, err);
+                    gathered.failed.push((name, err.to_string()));
+                }
+            }
+        }
+
+        gathered.completions.items = merge::merge(results, policy);
+        gathered
+    }
+}
+
+// This completes filenames
+//
+// What's being completed is the whole path before the cursor, as decided by
+// 'isfname', and every item includes the directory part of it. If Lua didn't
+// send the line, or the line doesn't end in the word it sent, we go by the word.
+//
+// Files starting with a dot are left out unless you pass `hidden = true`,
+// or you've already typed the dot.
+pub struct FileCompletionSource {
+    /// Shared with the handler, which fills it in from `buf_initialize`
+    isfname: Arc<RwLock<CharsetMatcher>>,
+}
+
+impl FileCompletionSource {
+    pub fn new(isfname: Arc<RwLock<CharsetMatcher>>) -> Self {
+        FileCompletionSource { isfname }
+    }
+
+    /// The path being typed, up to the cursor.
+    async fn typed_path(&self, ctx: &CompletionContext) -> String {
+        if let (Some(line), Some(cursor)) = (&ctx.line, ctx.cursor) {
+            let range = self.isfname.read().await.find(line, cursor.col);
+            let path = &line[range.start..range.cursor];
+            if path.ends_with(ctx.word.as_str()) {
+                return path.to_string();
+            }
+        }
+
+        ctx.word.clone()
+    }
+}
+
+impl Default for FileCompletionSource {
+    fn default() -> Self {
+        Self::new(Arc::new(RwLock::new(CharsetMatcher::default_for(
+            CharsetOption::IsFname,
+        ))))
+    }
+}
+
+impl Debug for FileCompletionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCompletionSource")
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl CompletionSource for FileCompletionSource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn charset(&self) -> CharsetOption {
+        CharsetOption::IsFname
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        vec![OptionSpec::new("hidden", OptionKind::Boolean, false)]
+    }
+
+    async fn complete(&self, ctx: &CompletionContext, opts: &SourceOptions) -> Result<Completions> {
+        let typed = self.typed_path(ctx).await;
+        let path_to_complete = Path::new(typed.as_str());
+
+        // Dotfiles only show up when asked for, or when you've started typing one anyway
+        let show_hidden = opts.bool("hidden")
+            || path_to_complete
+                .file_name()
+                .and_then(|tail| tail.to_str())
+                .is_some_and(|tail| tail.starts_with('.'));
+
+        // TODO: Definitely not handling all the cases.
+        // "/hello/world" -> "/hello"
+        // "README.m" -> $CWD
+        let path_tail = path_to_complete.file_name();
+
+        // Relative paths are relative to Neovim's cwd, not ours
+        let path_parent = match path_to_complete.parent() {
+            Some(parent) if parent != Path::new("") => ctx.cwd.join(parent),
+            _ => ctx.cwd.clone(),
+        };
+        info!(
+            "To Complete: {:?}, Path Parent: {:?}",
+            path_to_complete, path_parent
+        );
+
+        // Whatever was typed up to the last slash stays, so the item replaces the whole path
+        let typed_dir = match typed.rfind('/') {
+            Some(slash) => &typed[..=slash],
+            None => "",
+        };
+
+        let mut items = Vec::new();
+        let mut entries = fs::read_dir(&path_parent).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            trace!("Examining Path: {:?}", path);
+
+            let is_hidden = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with('.'));
+            if is_hidden && !show_hidden {
+                continue;
+            }
+
+            let mut score = 0;
+            if let Some(path_filter) = path_tail.and_then(|path_filter| path_filter.to_str()) {
+                if let Some(tail) = path.file_name().and_then(|tail| tail.to_str()) {
+                    match fuzzy::score(path_filter, tail) {
+                        Some(tail_score) => score = tail_score,
+                        None => continue,
+                    }
+                }
+            }
+
+            if let Some(relative_path) = pathdiff::diff_paths(&path, &path_parent) {
+                // A name that isn't UTF-8 can't go in a complete-item, so it's left out
+                let name = match relative_path.to_str() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+
+                items.push(CompletionItem {
+                    word: format!("{}{}", typed_dir, name),
+                    abbr: Some(String::from(name)).filter(|_| !typed_dir.is_empty()),
+                    menu: Some(String::from("[F]")),
+                    kind: Some(String::from(if is_dir { "d" } else { "f" })),
+                    score,
+                    ..Default::default()
+                });
+            }
+        }
+
+        Ok(Completions {
+            items,
+            is_incomplete: false,
+        })
+    }
+}
+
+/// The keyword the cursor is in or right after, e.g. `hel|lo` gives `hello`, as long
+/// as what's before the cursor is the `typed` word the request was for.
+fn word_at_cursor(
+    keywords: &KeywordMatcher,
+    line: &str,
+    col: usize,
+    typed: &str,
+) -> Option<String> {
+    let range = keywords.find(line, col as u64);
+    let word = &line[range.start..range.finish];
+
+    if word.is_empty() || line[range.start..range.cursor] != *typed {
+        None
+    } else {
+        Some(word.to_string())
+    }
+}
+
+/// Which buffers the buffer source takes words from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BufferScope {
+    /// Only the buffer being completed in
+    Current,
+
+    /// These buffers, e.g. `scope = { 1, 4 }`
+    Buffers(Vec<u64>),
+
+    /// Buffers shown in a window of the current tabpage
+    Visible,
+
+    /// Every buffer we have words for
+    All,
+}
+
+impl BufferScope {
+    /// Reads the `scope` option, which defaults to the current buffer.
+    fn from_opts(opts: &SourceOptions) -> Result<Self> {
+        match opts.get("scope") {
+            Some(scope) => Self::from_value(scope),
+            None => Ok(BufferScope::Current),
+        }
+    }
+
+    fn from_value(scope: &Value) -> Result<Self> {
+        if let Some(bufnrs) = scope.as_array() {
+            return bufnrs
+                .iter()
+                .map(|bufnr| {
+                    bufnr
+                        .as_u64()
+                        .ok_or_else(|| anyhow!("buffer: `scope` lists bufnrs, got {}", bufnr))
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(BufferScope::Buffers);
+        }
+
+        match scope.as_str() {
+            Some("current") => Ok(BufferScope::Current),
+            Some("visible") => Ok(BufferScope::Visible),
+            Some("all") => Ok(BufferScope::All),
+            _ => Err(anyhow!(
+                "buffer: `scope` must be 'current', 'visible', 'all' or a list of bufnrs, got {}",
+                scope
+            )),
+        }
+    }
+
+    /// The buffers to look in, with the current one first if it's included.
+    fn bufnrs(&self, ctx: &CompletionContext, known: impl Iterator<Item = u64>) -> Vec<u64> {
+        let mut bufnrs: Vec<u64> = match self {
+            BufferScope::Current => vec![ctx.bufnr],
+            BufferScope::Buffers(bufnrs) => bufnrs.clone(),
+            BufferScope::Visible => {
+                let mut visible = ctx.visible.clone();
+                visible.push(ctx.bufnr);
+                visible
+            }
+            BufferScope::All => known.collect(),
+        };
+
+        bufnrs.sort_unstable_by_key(|&bufnr| (bufnr != ctx.bufnr, bufnr));
+        bufnrs.dedup();
+        bufnrs
+    }
+}
+
+/// Completes words in open buffers
+///
+/// Has an `on_bytes` / `on_lines` callback to update the state
+/// of the words in open buffers.
+///
+/// This is super overkill and that's OK :) I just wanna learn Rust.
+///
+/// Lines are split into words using each buffer's 'iskeyword', so the words we
+/// store are the same ones `find_start` will try to complete.
+///
+/// Options:
+///     scope       where words come from: 'current' (default), 'visible', 'all',
+///                 or a list of bufnrs. A word in more than one buffer shows up
+///                 once, scored from the current buffer if it's in there.
+///
+///     min_length  words shorter than this (in characters) are left out, default 1
+#[derive(Default)]
+pub struct BufferCompletionSource {
+    pub word_store: RwLock<HashMap<u64, BufferWordStore>>,
+
+    /// Shared with the handler, which fills it in from `buf_initialize`
+    iskeyword_map: Arc<RwLock<HashMap<u64, KeywordMatcher>>>,
+
+    /// Used for buffers that haven't been initialized (yet)
+    default_keywords: KeywordMatcher,
+}
+
+impl BufferCompletionSource {
+    pub fn new(iskeyword_map: Arc<RwLock<HashMap<u64, KeywordMatcher>>>) -> Self {
+        BufferCompletionSource {
+            iskeyword_map,
+            ..Default::default()
+        }
+    }
+}
+
+impl Debug for BufferCompletionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // tokio's RwLock isn't Debug, and we can't await in here to peek inside it
+        f.debug_struct("BufferCompletionSource")
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl CompletionSource for BufferCompletionSource {
+    fn name(&self) -> &'static str {
+        "buffer"
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        fn check_scope(scope: &Value) -> Result<()> {
+            BufferScope::from_value(scope).map(|_| ())
+        }
+
+        vec![
+            OptionSpec::new("scope", OptionKind::Custom(check_scope), "current"),
+            OptionSpec::new("min_length", OptionKind::Count, 1),
+        ]
+    }
+
+    async fn complete(&self, ctx: &CompletionContext, opts: &SourceOptions) -> Result<Completions> {
+        let scope = BufferScope::from_opts(opts)?;
+        let min_length = opts.count("min_length").unwrap_or(1) as usize;
+
+        let typing = match (&ctx.line, ctx.cursor) {
+            (Some(line), Some(cursor)) => {
+                let iskeyword_map = self.iskeyword_map.read().await;
+                let keywords = iskeyword_map
+                    .get(&ctx.bufnr)
+                    .unwrap_or(&self.default_keywords);
+
+                word_at_cursor(keywords, line, cursor.col as usize, &ctx.word)
+            }
+            _ => None,
+        };
+
+        let word_store = self.word_store.read().await;
+
+        // Best match for each word, and whether it came from the current buffer
+        let mut matches: HashMap<String, (bool, i64)> = HashMap::new();
+        for bufnr in scope.bufnrs(ctx, word_store.keys().copied()) {
+            let buffer_word_store = match word_store.get(&bufnr) {
+                Some(buffer_word_store) => buffer_word_store,
+                None => continue,
+            };
+
+            // Distance to the cursor only means something in the buffer the cursor is in
+            let is_current = bufnr == ctx.bufnr;
+            let cursor_line = ctx.cursor.filter(|_| is_current).map(|cursor| cursor.line);
+
+            for (word, score) in buffer_word_store.get_ranked_matches(&ctx.word, cursor_line) {
+                if word.chars().count() < min_length {
+                    continue;
+                }
+
+                // What you're typing is in the buffer too, but it's only worth
+                // suggesting if it's somewhere else as well
+                if is_current
+                    && typing.as_ref() == Some(&word)
+                    && buffer_word_store.count(&word) <= 1
+                {
+                    continue;
+                }
+
+                let candidate = (is_current, score);
+                let best = matches.entry(word).or_insert(candidate);
+                *best = (*best).max(candidate);
+            }
+        }
+
+        Ok(Completions {
+            items: matches
+                .into_iter()
+                .map(|(word, (_, score))| CompletionItem {
+                    word,
+                    score,
+                    menu: Some(String::from("[B]")),
+                    kind: Some(String::from("w")),
+                    ..Default::default()
+                })
+                .collect(),
+            is_incomplete: false,
+        })
+    }
+
+    async fn on_detach(&self, bufnr: u64) {
+        self.word_store.write().await.remove(&bufnr);
+    }
+
+    async fn on_lines(&self, bufnr: u64, start_line: u64, final_line: u64, lines: &[String]) {
+        let iskeyword_map = self.iskeyword_map.read().await;
+        let keywords = iskeyword_map.get(&bufnr).unwrap_or(&self.default_keywords);
+
+        let new_lines: Vec<Vec<String>> = lines
+            .iter()
+            .map(|line| {
+                keywords
+                    .words(line)
+                    .into_iter()
+                    .map(|x| x.to_string())
+                    .collect()
+            })
+            .collect();
+
+        let mut word_store = self.word_store.write().await;
+        word_store
+            .entry(bufnr)
+            .or_default()
+            .splice(start_line, final_line, new_lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cursor;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_fuzzy_matches_sorted_best_first() {
+        let mut buffer_store = BufferWordStore::default();
+        buffer_store.update(
+            1,
+            vec![
+                String::from("hello_world"),
+                String::from("hollow"),
+                String::from("world"),
+            ],
+        );
+
+        let results = SourceResults {
+            weight: SourceWeight {
+                priority: 0,
+                max_items: None,
+            },
+            items: buffer_store
+                .get_fuzzy_matches("hw")
+                .into_iter()
+                .map(|(word, score)| CompletionItem {
+                    score,
+                    ..CompletionItem::new(word)
+                })
+                .collect(),
+        };
+        let items = merge::merge(vec![results], &MergePolicy::default());
+
+        let words: Vec<&str> = items.iter().map(|x| x.word.as_str()).collect();
+        assert_eq!(vec!["hello_world", "hollow"], words);
+    }
+
+    #[tokio::test]
+    async fn test_on_lines_uses_iskeyword() {
+        let iskeyword_map = Arc::new(RwLock::new(HashMap::new()));
+        let source = BufferCompletionSource::new(iskeyword_map.clone());
+        let line = vec![String::from("call foo(bar-baz,\tqux)")];
+
+        source.on_lines(1, 0, 1, &line).await;
+        let words = source.word_store.read().await[&1].get_exact_matches("");
+        let expected: HashSet<String> = vec!["call", "foo", "bar", "baz", "qux"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(expected, words);
+
+        iskeyword_map
+            .write()
+            .await
+            .insert(2, crate::nvim::iskeyword::transform("@,48-57,_,45"));
+        source.on_lines(2, 0, 1, &line).await;
+        let words = source.word_store.read().await[&2].get_exact_matches("b");
+        assert_eq!(
+            vec![String::from("bar-baz")]
+                .into_iter()
+                .collect::<HashSet<_>>(),
+            words
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retokenize_and_detach() {
+        let iskeyword_map = Arc::new(RwLock::new(HashMap::new()));
+        let source = BufferCompletionSource::new(iskeyword_map.clone());
+        let lines = vec![String::from("foo-bar"), String::from("baz")];
+        source.on_lines(1, 0, 0, &lines).await;
+
+        // What the handler does when 'iskeyword' changes
+        iskeyword_map
+            .write()
+            .await
+            .insert(1, crate::nvim::iskeyword::transform("@,48-57,_,45"));
+        source.on_lines(1, 0, 2, &lines).await;
+
+        let word_store = source.word_store.read().await;
+        let expected: HashSet<String> = vec!["foo-bar", "baz"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(expected, word_store[&1].get_exact_matches(""));
+        assert_eq!(2, word_store[&1].line_count());
+        drop(word_store);
+
+        source.on_detach(1).await;
+        assert!(source.word_store.read().await.get(&1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_skips_the_word_being_typed() {
+        let source = BufferCompletionSource::default();
+        let lines = vec![String::from("help hello"), String::from("hel")];
+        source.on_lines(1, 0, 0, &lines).await;
+
+        let ctx = CompletionContext {
+            cursor: Some(Cursor { line: 1, col: 3 }),
+            line: Some(String::from("hel")),
+            ..CompletionContext::for_test("hel")
+        };
+        let completions = source
+            .complete(&ctx, &SourceOptions::default())
+            .await
+            .unwrap();
+        let mut found: Vec<&str> = completions
+            .items
+            .iter()
+            .map(|item| item.word.as_str())
+            .collect();
+        found.sort_unstable();
+        assert_eq!(vec!["hello", "help"], found);
+
+        let key = KeywordMatcher::default();
+        assert_eq!(
+            Some(String::from("hello")),
+            word_at_cursor(&key, "a hello", 5, "hel")
+        );
+        assert_eq!(
+            Some(String::from("hello")),
+            word_at_cursor(&key, "hello b", 3, "hel")
+        );
+        assert_eq!(None, word_at_cursor(&key, "a  b", 2, ""));
+        assert_eq!(None, word_at_cursor(&key, "hello b", 3, "he"));
+    }
+
+    #[tokio::test]
+    async fn test_word_and_cursor_without_a_line() {
+        // What `_get_completions` sends when it's given a word: the cursor can be
+        // anywhere, even on a word that's in the buffer
+        let source = BufferCompletionSource::default();
+        source.on_lines(1, 0, 0, &[String::from("hello")]).await;
+
+        let ctx = CompletionContext {
+            cursor: Some(Cursor { line: 0, col: 0 }),
+            ..CompletionContext::for_test("hel")
+        };
+        let completions = source
+            .complete(&ctx, &SourceOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, completions.items.len());
+        assert_eq!("hello", completions.items[0].word);
+
+        let source = FileCompletionSource::default();
+        let ctx = CompletionContext {
+            word: String::from("R"),
+            ..ctx
+        };
+        assert_eq!("R", source.typed_path(&ctx).await);
+    }
+
+    #[tokio::test]
+    async fn test_file_completes_the_whole_path() {
+        let cwd = std::env::temp_dir().join(format!("rofl_file_test_{}", std::process::id()));
+        std::fs::create_dir_all(cwd.join("sub")).unwrap();
+        std::fs::write(cwd.join("sub").join("file.txt"), "").unwrap();
+
+        let source = FileCompletionSource::default();
+        let line = "edit ./sub/fi";
+        let ctx = CompletionContext {
+            cwd: cwd.clone(),
+            cursor: Some(Cursor {
+                line: 0,
+                col: line.len() as u64,
+            }),
+            line: Some(String::from(line)),
+            ..CompletionContext::for_test("fi")
+        };
+
+        let completions = source
+            .complete(&ctx, &SourceOptions::default())
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&cwd).unwrap();
+
+        assert_eq!(1, completions.items.len());
+        assert_eq!("./sub/file.txt", completions.items[0].word);
+        assert_eq!(Some(String::from("file.txt")), completions.items[0].abbr);
+    }
+
+    #[tokio::test]
+    async fn test_file_typed_path_in_the_middle_of_a_character() {
+        let source = FileCompletionSource::default();
+        let line = "edit ./dé/x";
+        let typed_path = |col: u64| {
+            let ctx = CompletionContext {
+                cursor: Some(Cursor { line: 0, col }),
+                line: Some(String::from(line)),
+                ..CompletionContext::for_test("")
+            };
+            let source = &source;
+            async move { source.typed_path(&ctx).await }
+        };
+
+        // Byte 9 is the second half of `é`
+        assert_eq!("./d", typed_path(9).await);
+        assert_eq!("./dé", typed_path(10).await);
+        assert_eq!("./dé/x", typed_path(100).await);
+    }
+
+    #[tokio::test]
+    async fn test_file_prefers_the_word_it_was_sent() {
+        let source = FileCompletionSource::default();
+        let ctx = CompletionContext {
+            cursor: Some(Cursor { line: 0, col: 5 }),
+            line: Some(String::from("hello world")),
+            ..CompletionContext::for_test("READ")
+        };
+
+        assert_eq!("READ", source.typed_path(&ctx).await);
+    }
+
+    #[tokio::test]
+    async fn test_file_hidden() {
+        let cwd = std::env::temp_dir().join(format!("rofl_hidden_test_{}", std::process::id()));
+        std::fs::create_dir_all(&cwd).unwrap();
+        std::fs::write(cwd.join(".hidden"), "").unwrap();
+        std::fs::write(cwd.join("shown"), "").unwrap();
+
+        let source = FileCompletionSource::default();
+        let complete = |word: &str, hidden: bool| {
+            let source = &source;
+            let ctx = CompletionContext {
+                cwd: cwd.clone(),
+                ..CompletionContext::for_test(word)
+            };
+            let opts = Value::Map(vec![(Value::from("hidden"), Value::from(hidden))]);
+            let opts = SourceOptions::parse("file", &source.options(), &opts).unwrap();
+
+            async move {
+                let mut words: Vec<String> = source
+                    .complete(&ctx, &opts)
+                    .await
+                    .unwrap()
+                    .items
+                    .into_iter()
+                    .map(|item| item.word)
+                    .collect();
+                words.sort_unstable();
+                words
+            }
+        };
+
+        let shown = complete("./", false).await;
+        let all = complete("./", true).await;
+        let typed_a_dot = complete("./.h", false).await;
+        std::fs::remove_dir_all(&cwd).unwrap();
+
+        assert_eq!(vec!["./shown"], shown);
+        assert_eq!(vec!["./.hidden", "./shown"], all);
+        assert_eq!(vec!["./.hidden"], typed_a_dot);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_skips_names_that_are_not_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let cwd = std::env::temp_dir().join(format!("rofl_utf8_test_{}", std::process::id()));
+        std::fs::create_dir_all(&cwd).unwrap();
+        std::fs::write(cwd.join(OsStr::from_bytes(b"bad\xff")), "").unwrap();
+        std::fs::write(cwd.join("good"), "").unwrap();
+
+        let source = FileCompletionSource::default();
+        let ctx = CompletionContext {
+            cwd: cwd.clone(),
+            ..CompletionContext::for_test("./")
+        };
+        let completions = source.complete(&ctx, &SourceOptions::default()).await;
+        std::fs::remove_dir_all(&cwd).unwrap();
+
+        let words: Vec<String> = completions
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|item| item.word)
+            .collect();
+        assert_eq!(vec!["./good"], words);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_min_length() {
+        let source = BufferCompletionSource::default();
+        source
+            .on_lines(1, 0, 0, &[String::from("if iffy ifdef")])
+            .await;
+
+        let ctx = CompletionContext::for_test("if");
+        let opts = Value::Map(vec![(Value::from("min_length"), Value::from(4))]);
+        let opts = SourceOptions::parse("buffer", &source.options(), &opts).unwrap();
+
+        let mut words: Vec<String> = source
+            .complete(&ctx, &opts)
+            .await
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|item| item.word)
+            .collect();
+        words.sort_unstable();
+        assert_eq!(vec!["ifdef", "iffy"], words);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_scope() {
+        let source = BufferCompletionSource::default();
+        source.on_lines(1, 0, 0, &[String::from("hello")]).await;
+        source
+            .on_lines(2, 0, 0, &[String::from("help hello")])
+            .await;
+        source.on_lines(3, 0, 0, &[String::from("helium")]).await;
+
+        let ctx = CompletionContext {
+            cursor: Some(Cursor { line: 0, col: 0 }),
+            visible: vec![2],
+            ..CompletionContext::for_test("hel")
+        };
+        let complete = |scope: Value| {
+            let source = &source;
+            let ctx = &ctx;
+            async move {
+                let opts = Value::Map(vec![(Value::from("scope"), scope)]);
+                let opts = SourceOptions::parse("buffer", &source.options(), &opts).unwrap();
+                let mut found: Vec<(String, i64)> = source
+                    .complete(ctx, &opts)
+                    .await
+                    .unwrap()
+                    .items
+                    .into_iter()
+                    .map(|item| (item.word, item.score))
+                    .collect();
+                found.sort_unstable();
+                found
+            }
+        };
+
+        let words = |found: Vec<(String, i64)>| -> Vec<String> {
+            found.into_iter().map(|(word, _)| word).collect()
+        };
+
+        assert_eq!(vec!["hello"], words(complete(Value::from("current")).await));
+        assert_eq!(
+            vec!["hello", "help"],
+            words(complete(Value::from("visible")).await)
+        );
+        assert_eq!(
+            vec!["helium", "hello", "help"],
+            words(complete(Value::from("all")).await)
+        );
+        assert_eq!(
+            vec!["helium"],
+            words(complete(Value::Array(vec![Value::from(3)])).await)
+        );
+
+        // `hello` is in both, and gets scored where the cursor is
+        let current = complete(Value::from("current")).await;
+        let visible = complete(Value::from("visible")).await;
+        assert_eq!(current[0], visible[0]);
+
+        let opts = Value::Map(vec![(Value::from("scope"), Value::from("everywhere"))]);
+        assert!(SourceOptions::parse("buffer", &source.options(), &opts).is_err());
+    }
+
+    #[test]
+    fn test_registry_lookup_by_name() {
+        let mut registry = SourceRegistry::default();
+        registry.register(FileCompletionSource::default());
+        registry.register(BufferCompletionSource::default());
+
+        assert!(registry.contains("buffer"));
+        assert!(registry.contains("file"));
+        assert!(!registry.contains("files"));
+        assert_eq!(vec!["buffer", "file"], registry.names());
+    }
+
+    #[test]
+    fn test_registry_options() {
+        let mut registry = SourceRegistry::default();
+        registry.register(BufferCompletionSource::default());
+
+        let opts = Value::Map(vec![(Value::from("priority"), Value::from(5))]);
+        let options = registry.options("buffer", &opts).unwrap();
+        assert_eq!(Some(5), options.integer("priority"));
+        assert_eq!(Some(&Value::from("current")), options.get("scope"));
+        assert_eq!(Some(1), options.count("min_length"));
+
+        let opts = Value::Map(vec![(Value::from("hidden"), Value::from(true))]);
+        assert!(registry.options("buffer", &opts).is_err());
+        assert!(registry.options("file", &Value::Map(vec![])).is_err());
+    }
+
+    #[derive(Debug)]
+    struct SlowSource;
+
+    #[async_trait]
+    impl CompletionSource for SlowSource {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(10)
+        }
+
+        async fn complete(
+            &self,
+            _ctx: &CompletionContext,
+            _opts: &SourceOptions,
+        ) -> Result<Completions> {
+            time::delay_for(Duration::from_millis(500)).await;
+
+            Ok(Completions {
+                items: vec![CompletionItem::new("too_late")],
+                is_incomplete: false,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_source_is_dropped() {
+        let mut registry = SourceRegistry::default();
+        registry.register(SlowSource {});
+        registry.register(BufferCompletionSource::default());
+
+        registry
+            .get("buffer")
+            .unwrap()
+            .source
+            .on_lines(1, 0, 0, &[String::from("hello")])
+            .await;
+
+        let ctx = Arc::new(CompletionContext::for_test("hel"));
+
+        let gathered = registry
+            .complete(
+                &[
+                    (String::from("slow"), SourceOptions::default()),
+                    (String::from("buffer"), SourceOptions::default()),
+                ],
+                ctx,
+                &MergePolicy::default(),
+            )
+            .await;
+
+        assert_eq!(vec![String::from("slow")], gathered.timed_out);
+        assert_eq!(1, gathered.completions.items.len());
+        assert_eq!("hello", gathered.completions.items[0].word);
+        assert_eq!(
+            Some(Value::Map(vec![(
+                Value::from("source"),
+                Value::from("buffer")
+            )])),
+            gathered.completions.items[0].user_data
+        );
+    }
+
+    /// Never finishes, and says when its work got dropped.
+    #[derive(Debug)]
+    struct StuckSource {
+        dropped: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    struct SetOnDrop(Arc<std::sync::atomic::AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl CompletionSource for StuckSource {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(60)
+        }
+
+        async fn complete(
+            &self,
+            _ctx: &CompletionContext,
+            _opts: &SourceOptions,
+        ) -> Result<Completions> {
+            let _guard = SetOnDrop(self.dropped.clone());
+            future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropping_the_request_aborts_the_sources() {
+        let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let mut registry = SourceRegistry::default();
+        registry.register(StuckSource {
+            dropped: dropped.clone(),
+        });
+
+        let ctx = Arc::new(CompletionContext {
+            request_id: Some(1),
+            ..CompletionContext::for_test("hel")
+        });
+
+        // Gives up on the request, like a cancelled one does
+        let requested = [(String::from("stuck"), SourceOptions::default())];
+        let gathered = time::timeout(
+            Duration::from_millis(20),
+            registry.complete(&requested, ctx, &MergePolicy::default()),
+        )
+        .await;
+        assert!(gathered.is_err());
+
+        time::delay_for(Duration::from_millis(20)).await;
+        assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_option() {
+        let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let mut registry = SourceRegistry::default();
+        registry.register(StuckSource {
+            dropped: dropped.clone(),
+        });
+
+        // Its own deadline is a minute, the request doesn't want to wait that long
+        let opts = Value::Map(vec![(Value::from("timeout_ms"), Value::from(10))]);
+        let requested = [(
+            String::from("stuck"),
+            registry.options("stuck", &opts).unwrap(),
+        )];
+        let gathered = time::timeout(
+            Duration::from_secs(5),
+            registry.complete(
+                &requested,
+                Arc::new(CompletionContext::for_test("hel")),
+                &MergePolicy::default(),
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(vec![String::from("stuck")], gathered.timed_out);
+        assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_item_to_complete_dict() {
+        let item = CompletionItem {
+            word: String::from("hello"),
+            menu: Some(String::from("[B]")),
+            kind: Some(String::from("w")),
+            dup: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Value::Map(vec![
+                (Value::from("word"), Value::from("hello")),
+                (Value::from("menu"), Value::from("[B]")),
+                (Value::from("kind"), Value::from("w")),
+                (Value::from("dup"), Value::from(1)),
+            ]),
+            Value::from(item)
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// The options a request can give each source, e.g. `buffer = { scope = 'all' }`.
+//
+// Every source says which options it takes, what they look like, and what they
+// are when the request leaves them out. Requests get checked against that before
+// any source runs, so a typo or a bad value is an error for the request instead
+// of silently doing the default thing.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use nvim_rs::Value;
+
+use crate::rpc::as_table;
+
+/// What an option's value has to look like.
+#[derive(Debug, Clone, Copy)]
+pub enum OptionKind {
+    Boolean,
+    Integer,
+
+    /// A whole number that can't be negative
+    Count,
+
+    /// Anything else, checked by the source, e.g. `scope` can be a string or a list
+    Custom(fn(&Value) -> Result<()>),
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub kind: OptionKind,
+
+    /// Used when the request leaves the option out. `Nil` means it stays unset.
+    pub default: Value,
+}
+
+impl OptionSpec {
+    pub fn new(name: &'static str, kind: OptionKind, default: impl Into<Value>) -> Self {
+        OptionSpec {
+            name,
+            kind,
+            default: default.into(),
+        }
+    }
+
+    /// An option with no default, so sources can tell that it wasn't given.
+    pub fn optional(name: &'static str, kind: OptionKind) -> Self {
+        Self::new(name, kind, Value::Nil)
+    }
+
+    fn check(&self, source: &str, value: &Value) -> Result<()> {
+        let expected = match self.kind {
+            OptionKind::Boolean if value.is_bool() => return Ok(()),
+            OptionKind::Boolean => "a boolean",
+            OptionKind::Integer if value.as_i64().is_some() => return Ok(()),
+            OptionKind::Integer => "a number",
+            OptionKind::Count if value.as_u64().is_some() => return Ok(()),
+            OptionKind::Count => "a count",
+            OptionKind::Custom(check) => return check(value),
+        };
+
+        Err(anyhow!(
+            "{}: `{}` should be {}, got {}",
+            source,
+            self.name,
+            expected,
+            value
+        ))
+    }
+}
+
+/// Options every source takes, which decide how long it gets and how its results
+/// get merged with the others.
+pub fn common_options() -> Vec<OptionSpec> {
+    vec![
+        OptionSpec::optional("priority", OptionKind::Integer),
+        OptionSpec::optional("max_items", OptionKind::Count),
+        OptionSpec::optional("timeout_ms", OptionKind::Count),
+    ]
+}
+
+/// A source's options for one request, checked and with the defaults filled in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceOptions {
+    values: BTreeMap<&'static str, Value>,
+}
+
+impl SourceOptions {
+    /// `opts` is what the request sent for the source, which is a table,
+    /// or an empty one if the source was just turned on with `true`.
+    pub fn parse(source: &str, specs: &[OptionSpec], opts: &Value) -> Result<Self> {
+        let given = as_table(opts)
+            .ok_or_else(|| anyhow!("{}: options should be a table, got {}", source, opts))?;
+
+        let mut values = BTreeMap::new();
+        for (key, value) in given {
+            let spec = key
+                .as_str()
+                .and_then(|key| specs.iter().find(|spec| spec.name == key))
+                .ok_or_else(|| {
+                    let names: Vec<&str> = specs.iter().map(|spec| spec.name).collect();
+                    anyhow!(
+                        "{}: unknown option {}, it takes {}",
+                        source,
+                        key,
+                        names.join(", ")
+                    )
+                })?;
+
+            if value.is_nil() {
+                continue;
+            }
+
+            spec.check(source, value)?;
+            values.insert(spec.name, value.clone());
+        }
+
+        for spec in specs {
+            if !spec.default.is_nil() {
+                values
+                    .entry(spec.name)
+                    .or_insert_with(|| spec.default.clone());
+            }
+        }
+
+        Ok(SourceOptions { values })
+    }
+
+    /// `None` if it wasn't given and has no default.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    pub fn bool(&self, name: &str) -> bool {
+        self.get(name).and_then(Value::as_bool).unwrap_or(false)
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(Value::as_i64)
+    }
+
+    pub fn count(&self, name: &str) -> Option<u64> {
+        self.get(name).and_then(Value::as_u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::map;
+
+    fn specs() -> Vec<OptionSpec> {
+        fn not_empty(value: &Value) -> Result<()> {
+            match value.as_str() {
+                Some(string) if !string.is_empty() => Ok(()),
+                _ => Err(anyhow!("test: `name` should be a name, got {}", value)),
+            }
+        }
+
+        vec![
+            OptionSpec::new("hidden", OptionKind::Boolean, false),
+            OptionSpec::new("min_length", OptionKind::Count, 1),
+            OptionSpec::optional("name", OptionKind::Custom(not_empty)),
+        ]
+    }
+
+    #[test]
+    fn test_defaults() {
+        let options = SourceOptions::parse("test", &specs(), &Value::Map(vec![])).unwrap();
+        assert!(!options.bool("hidden"));
+        assert_eq!(Some(1), options.count("min_length"));
+        assert_eq!(None, options.get("name"));
+
+        let options = SourceOptions::parse("test", &specs(), &Value::Array(vec![])).unwrap();
+        assert_eq!(Some(1), options.count("min_length"));
+    }
+
+    #[test]
+    fn test_given_values() {
+        let opts = map(vec![
+            ("hidden", Value::from(true)),
+            ("min_length", Value::from(3)),
+            ("name", Value::from("rofl")),
+        ]);
+        let options = SourceOptions::parse("test", &specs(), &opts).unwrap();
+
+        assert!(options.bool("hidden"));
+        assert_eq!(Some(3), options.count("min_length"));
+        assert_eq!(Some(&Value::from("rofl")), options.get("name"));
+    }
+
+    #[test]
+    fn test_bad_values() {
+        let error = |opts| {
+            SourceOptions::parse("test", &specs(), &opts)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            "test: unknown option \"hiden\", it takes hidden, min_length, name",
+            error(map(vec![("hiden", Value::from(true))]))
+        );
+        assert_eq!(
+            "test: `min_length` should be a count, got -1",
+            error(map(vec![("min_length", Value::from(-1))]))
+        );
+        assert_eq!(
+            "test: `hidden` should be a boolean, got \"yes\"",
+            error(map(vec![("hidden", Value::from("yes"))]))
+        );
+        assert_eq!(
+            "test: `name` should be a name, got \"\"",
+            error(map(vec![("name", Value::from(""))]))
+        );
+        assert_eq!(
+            "test: options should be a table, got 1",
+            error(Value::from(1))
+        );
+    }
+}
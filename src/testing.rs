@@ -0,0 +1,13 @@
+// Helpers for the tests of more than one module.
+
+use nvim_rs::Value;
+
+/// A map with string keys, the way a Lua table comes through msgpack.
+pub fn map(pairs: Vec<(&str, Value)>) -> Value {
+    Value::Map(
+        pairs
+            .into_iter()
+            .map(|(key, val)| (Value::from(key), val))
+            .collect(),
+    )
+}
@@ -0,0 +1,286 @@
+// The words of one buffer, for the buffer source.
+//
+// Each line's words are kept in order, so an edit only has to re-tokenize the
+// lines it touched and the rest just shift, and every word is counted in a
+// `Trie`, so a completion only looks at the words that start with the right
+// character before fuzzy matching them.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{collections::Trie, fuzzy};
+
+#[derive(Debug, Clone, Default)]
+pub struct BufferWordStore {
+    /// The words on each line, indexed by (0-based) line number
+    lines_to_words: Vec<Vec<String>>,
+
+    /// Every word in the buffer, counted once per occurrence
+    words: Trie,
+}
+
+impl BufferWordStore {
+    pub fn update(&mut self, line: u64, words: Vec<String>) {
+        self.splice(line, line + 1, vec![words]);
+    }
+
+    /// Replace the words for lines `start..old_end` with `new_lines`, the same way
+    /// `nvim_buf_attach` reports changes.
+    ///
+    /// Everything after `old_end` shifts up or down to make room, so deleting or
+    /// inserting lines in the middle of the buffer keeps the rest lined up.
+    pub fn splice(&mut self, start: u64, old_end: u64, new_lines: Vec<Vec<String>>) {
+        let start = start as usize;
+
+        // We might not have seen the lines before this change, so pretend they were empty
+        if start > self.lines_to_words.len() {
+            self.lines_to_words.resize(start, Vec::new());
+        }
+
+        let old_end = (old_end as usize).clamp(start, self.lines_to_words.len());
+
+        for word in new_lines.iter().flatten() {
+            self.words.insert(word);
+        }
+
+        let removed: Vec<Vec<String>> = self
+            .lines_to_words
+            .splice(start..old_end, new_lines)
+            .collect();
+
+        for word in removed.iter().flatten() {
+            self.words.remove(word);
+        }
+    }
+
+    /// How many times `word` is in the buffer.
+    pub fn count(&self, word: &str) -> u64 {
+        self.words.count(word)
+    }
+
+    pub fn line_count(&self) -> u64 {
+        self.lines_to_words.len() as u64
+    }
+
+    pub fn get_exact_matches(&self, prefix: &str) -> HashSet<String> {
+        self.words
+            .with_prefix(prefix)
+            .into_iter()
+            .map(|(word, _)| word)
+            .collect()
+    }
+
+    pub fn get_fuzzy_matches(&self, pattern: &str) -> Vec<(String, i64)> {
+        fuzzy::first_char_prefixes(pattern)
+            .iter()
+            .flat_map(|prefix| self.words.with_prefix(prefix))
+            .filter_map(|(word, _)| fuzzy::score(pattern, &word).map(|score| (word, score)))
+            .collect()
+    }
+
+    /// How many lines away the nearest occurrence of each candidate is from `line`.
+    ///
+    /// Walks outwards from `line` and stops as soon as every candidate has been seen,
+    /// so words near the cursor are cheap to find.
+    pub fn distances_from(&self, line: u64, candidates: &HashSet<&str>) -> HashMap<String, u64> {
+        let mut distances = HashMap::new();
+        let last_line = self.line_count();
+
+        for distance in 0..=line.max(last_line) {
+            if distances.len() == candidates.len() {
+                break;
+            }
+
+            let above = line.checked_sub(distance);
+            let below = if distance > 0 {
+                Some(line + distance)
+            } else {
+                None
+            };
+
+            for words in above
+                .into_iter()
+                .chain(below)
+                .filter_map(|l| self.lines_to_words.get(l as usize))
+            {
+                for word in words {
+                    if candidates.contains(word.as_str()) && !distances.contains_key(word) {
+                        distances.insert(word.clone(), distance);
+                    }
+                }
+            }
+        }
+
+        distances
+    }
+
+    /// Fuzzy matches, with bonus points for being close to `cursor_line` and for
+    /// showing up a lot in the buffer.
+    pub fn get_ranked_matches(
+        &self,
+        pattern: &str,
+        cursor_line: Option<u64>,
+    ) -> Vec<(String, i64)> {
+        let matches = self.get_fuzzy_matches(pattern);
+
+        let distances = match cursor_line {
+            Some(line) => {
+                let candidates = matches.iter().map(|(word, _)| word.as_str()).collect();
+                self.distances_from(line, &candidates)
+            }
+            None => HashMap::new(),
+        };
+
+        matches
+            .into_iter()
+            .map(|(word, score)| {
+                let count = self.words.count(&word);
+                let bonus = proximity_bonus(distances.get(&word).copied()) + frequency_bonus(count);
+
+                (word, score + bonus)
+            })
+            .collect()
+    }
+}
+
+/// Same line is worth the most, and it tapers off every time the distance doubles.
+fn proximity_bonus(distance: Option<u64>) -> i64 {
+    match distance {
+        Some(distance) => (30 - 5 * i64::from((distance + 1).ilog2())).max(0),
+        None => 0,
+    }
+}
+
+/// A word used all over the buffer is probably one you want, but don't let it
+/// drown out the fuzzy score.
+fn frequency_bonus(count: u64) -> i64 {
+    if count == 0 {
+        return 0;
+    }
+
+    (2 * i64::from(count.ilog2())).min(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_updater() {
+        let mut buffer_store = BufferWordStore::default();
+
+        buffer_store.update(1, vec![String::from("hello"), String::from("world")]);
+        buffer_store.update(2, vec![String::from("world")]);
+
+        assert_eq!(HashSet::new(), buffer_store.get_exact_matches("asdf"));
+
+        let mut hello_match = HashSet::new();
+        hello_match.insert(String::from("hello"));
+        assert_eq!(hello_match, buffer_store.get_exact_matches("hel"));
+    }
+
+    #[test]
+    fn test_removing_line() {
+        let mut buffer_store = BufferWordStore::default();
+
+        buffer_store.update(1, vec![String::from("hello"), String::from("world")]);
+        buffer_store.update(2, vec![String::from("world")]);
+        buffer_store.update(1, vec![String::from("world")]);
+
+        dbg!(&buffer_store);
+
+        assert_eq!(HashSet::new(), buffer_store.get_exact_matches("hel"));
+    }
+
+    #[test]
+    fn test_closer_words_rank_higher() {
+        let mut buffer_store = BufferWordStore::default();
+        buffer_store.update(0, vec![String::from("hello_far")]);
+        for line in 1..40 {
+            buffer_store.update(line, vec![String::from("filler")]);
+        }
+        buffer_store.update(40, vec![String::from("hello_near")]);
+
+        let score_of = |cursor_line| {
+            let matches = buffer_store.get_ranked_matches("hel", Some(cursor_line));
+            let far = matches.iter().find(|(w, _)| w == "hello_far").unwrap().1;
+            let near = matches.iter().find(|(w, _)| w == "hello_near").unwrap().1;
+            (far, near)
+        };
+
+        let (far, near) = score_of(41);
+        assert!(near > far);
+
+        let (far, near) = score_of(1);
+        assert!(far > near);
+    }
+
+    #[test]
+    fn test_distances_from() {
+        let mut buffer_store = BufferWordStore::default();
+        buffer_store.update(0, vec![String::from("hello")]);
+        buffer_store.update(5, vec![String::from("world")]);
+        buffer_store.update(9, vec![String::from("hello")]);
+
+        let candidates = vec!["hello", "world", "missing"].into_iter().collect();
+        let distances = buffer_store.distances_from(7, &candidates);
+
+        assert_eq!(Some(&2), distances.get("hello"));
+        assert_eq!(Some(&2), distances.get("world"));
+        assert_eq!(None, distances.get("missing"));
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_splice_deleted_lines() {
+        let mut buffer_store = BufferWordStore::default();
+        buffer_store.splice(0, 0, vec![words("hello"), words("goodbye"), words("world")]);
+
+        // dd on the middle line
+        buffer_store.splice(1, 2, vec![]);
+        assert_eq!(2, buffer_store.line_count());
+        assert!(buffer_store.get_exact_matches("good").is_empty());
+        assert_eq!(1, buffer_store.get_exact_matches("wor").len());
+
+        // and the rest of the buffer
+        buffer_store.splice(0, 2, vec![]);
+        assert_eq!(0, buffer_store.line_count());
+        assert!(buffer_store.get_exact_matches("").is_empty());
+    }
+
+    #[test]
+    fn test_splice_inserted_lines_shift_the_rest() {
+        let mut buffer_store = BufferWordStore::default();
+        buffer_store.splice(0, 0, vec![words("hello"), words("world")]);
+
+        // `o` on the first line, then type in it
+        buffer_store.splice(1, 1, vec![words("")]);
+        buffer_store.splice(1, 2, vec![words("middle")]);
+        assert_eq!(3, buffer_store.line_count());
+
+        let candidates = vec!["hello", "middle", "world"].into_iter().collect();
+        let distances = buffer_store.distances_from(2, &candidates);
+        assert_eq!(Some(&0), distances.get("world"));
+        assert_eq!(Some(&1), distances.get("middle"));
+        assert_eq!(Some(&2), distances.get("hello"));
+
+        // Changing the moved line only touches its new position
+        buffer_store.splice(2, 3, vec![words("planet")]);
+        assert!(buffer_store.get_exact_matches("wor").is_empty());
+        assert_eq!(1, buffer_store.get_exact_matches("hel").len());
+    }
+
+    #[test]
+    fn test_splice_keeps_counts_for_repeated_words() {
+        let mut buffer_store = BufferWordStore::default();
+        buffer_store.splice(0, 0, vec![words("hello hello"), words("hello")]);
+        buffer_store.splice(0, 1, vec![]);
+
+        assert_eq!(1, buffer_store.get_exact_matches("hel").len());
+
+        buffer_store.splice(0, 1, vec![]);
+        assert!(buffer_store.get_exact_matches("hel").is_empty());
+    }
+}